@@ -0,0 +1,246 @@
+//! A shared, mutable reference to an immutable value, updated by compare-and-set.
+//!
+//! Readers take a snapshot with `load` and keep it as long as they like, writers build the next
+//! value from a snapshot and install it only if nobody else got there first. The collections in
+//! this crate share structure through `Rc` by default, so an `Atom` of one of them stays on one
+//! thread. With the `sync` feature they share it through `Arc` instead, and an `Arc<Atom<Map<K, V>>>`
+//! can be handed to any number of threads.
+//!
+//! The value lives behind an atomic pointer: `load` and `compare_and_set` are single atomic
+//! operations and never block each other. A writer that replaced a value waits for loads still in
+//! flight, a few instructions each, before giving up its reference to the old one.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+/// Names a watcher so it can be removed again.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct WatchId(usize);
+
+type Watcher<T> = dyn Fn(&T, &T) + Send + Sync;
+
+pub struct Atom<T> {
+    //Owns one strong count of the `Arc` it points to
+    value: AtomicPtr<T>,
+    //Loads between reading the pointer and taking their own count of it
+    loading: AtomicUsize,
+    watchers: RwLock<Vec<(WatchId, Box<Watcher<T>>)>>,
+    next_watch: AtomicUsize,
+    //Shared across threads like the `Arc`s it hands out, so only for `Send` and `Sync` values
+    owns: PhantomData<Arc<T>>,
+}
+
+impl<T> Atom<T> {
+    pub fn new(value: T) -> Self {
+        Atom {
+            value: AtomicPtr::new(Arc::into_raw(Arc::new(value)) as *mut T),
+            loading: AtomicUsize::new(0),
+            watchers: RwLock::new(vec![]),
+            next_watch: AtomicUsize::new(0),
+            owns: PhantomData,
+        }
+    }
+
+    /// The current value. Later updates replace the atom's pointer and leave the snapshot alone.
+    pub fn load(&self) -> Arc<T> {
+        self.loading.fetch_add(1, Ordering::SeqCst);
+        let value = self.value.load(Ordering::SeqCst);
+        //The atom's own count keeps the value alive, writers wait for `loading` before releasing it
+        unsafe { Arc::increment_strong_count(value) };
+        self.loading.fetch_sub(1, Ordering::SeqCst);
+        unsafe { Arc::from_raw(value) }
+    }
+
+    /// Installs `new` if the atom still holds `current`, compared by pointer. Returns whether it did.
+    pub fn compare_and_set(&self, current: &Arc<T>, new: T) -> bool {
+        self.set_if(current, Arc::new(new))
+    }
+
+    /// Installs `f` applied to the current value, retrying with the latest value whenever another
+    /// thread updated the atom in between, so `f` may run more than once. Returns the installed value.
+    pub fn swap<F: Fn(&T) -> T>(&self, f: F) -> Arc<T> {
+        loop {
+            let current = self.load();
+            let new = Arc::new(f(&current));
+            if self.set_if(&current, new.clone()) {
+                return new;
+            }
+        }
+    }
+
+    /// Installs `new` whatever the atom holds and returns the value it replaced.
+    pub fn reset(&self, new: T) -> Arc<T> {
+        let new = Arc::new(new);
+        let old = self.value.swap(Arc::into_raw(new.clone()) as *mut T, Ordering::SeqCst);
+        let old = self.release(old);
+        self.notify(&old, &new);
+        old
+    }
+
+    /// Calls `f(old, new)` after every update. Watchers run on the updating thread after the new value is
+    /// installed, so updates from several threads can reach them concurrently and out of order.
+    pub fn watch<F: Fn(&T, &T) + Send + Sync + 'static>(&self, f: F) -> WatchId {
+        let id = WatchId(self.next_watch.fetch_add(1, Ordering::Relaxed));
+        self.watchers.write().unwrap().push((id, Box::new(f)));
+        id
+    }
+
+    /// Removes a watcher, returning whether it was still there.
+    pub fn unwatch(&self, id: WatchId) -> bool {
+        let mut watchers = self.watchers.write().unwrap();
+        let before = watchers.len();
+        watchers.retain(|&(watch, _)| watch != id);
+        watchers.len() != before
+    }
+
+    fn set_if(&self, current: &Arc<T>, new: Arc<T>) -> bool {
+        let raw = Arc::into_raw(new.clone()) as *mut T;
+        match self.value.compare_exchange(Arc::as_ptr(current) as *mut T, raw, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(old) => {
+                self.release(old);
+                self.notify(current, &new);
+                true
+            }
+            Err(_) => {
+                drop(unsafe { Arc::from_raw(raw) });
+                false
+            }
+        }
+    }
+
+    //Takes back the atom's count of a value it no longer points to. A load that read the old
+    //pointer is counted in `loading` until it holds its own count, so wait for those to finish.
+    fn release(&self, old: *mut T) -> Arc<T> {
+        while self.loading.load(Ordering::SeqCst) != 0 {
+            thread::yield_now();
+        }
+        unsafe { Arc::from_raw(old) }
+    }
+
+    fn notify(&self, old: &T, new: &T) {
+        for (_, watcher) in self.watchers.read().unwrap().iter() {
+            watcher(old, new);
+        }
+    }
+}
+
+impl<T> Drop for Atom<T> {
+    fn drop(&mut self) {
+        drop(unsafe { Arc::from_raw(*self.value.get_mut()) });
+    }
+}
+
+#[test]
+fn atom_single_thread() {
+    use std::sync::Mutex;
+    use map::Map;
+
+    let atom = Atom::new(Map::<&str, i32>::new());
+    let before = atom.load();
+    atom.swap(|map| map.insert("a", 1).0);
+    assert!(before.is_empty());
+    assert_eq!(atom.load()["a"], 1);
+
+    //A stale snapshot loses, the current one wins
+    assert!(!atom.compare_and_set(&before, Map::new()));
+    let current = atom.load();
+    assert!(atom.compare_and_set(&current, current.insert("b", 2).0));
+    assert_eq!(atom.reset(Map::new()).len(), 2);
+    assert!(atom.load().is_empty());
+
+    let seen = Arc::new(Mutex::new(vec![]));
+    let counter = Atom::new(0);
+    let log = seen.clone();
+    let watch = counter.watch(move |&old, &new| log.lock().unwrap().push((old, new)));
+    counter.swap(|n| n + 1);
+    counter.reset(10);
+    assert!(!counter.compare_and_set(&Arc::new(10), 11));
+    assert!(counter.unwatch(watch));
+    assert!(!counter.unwatch(watch));
+    counter.swap(|n| n + 1);
+    assert_eq!(*seen.lock().unwrap(), vec![(0, 1), (1, 10)]);
+    assert_eq!(*counter.load(), 11);
+}
+
+#[test]
+fn atom_threads() {
+    let atom = Arc::new(Atom::new(vec![]));
+    let watched = Arc::new(AtomicUsize::new(0));
+    let count = watched.clone();
+    atom.watch(move |old: &Vec<usize>, new: &Vec<usize>| {
+        assert_eq!(old.len() + 1, new.len());
+        count.fetch_add(1, Ordering::SeqCst);
+    });
+
+    let threads: Vec<_> = (0..8).map(|thread| {
+        let atom = atom.clone();
+        thread::spawn(move || {
+            for i in 0..500 {
+                if i % 2 == 0 {
+                    atom.swap(|items| { let mut items = items.clone(); items.push(thread); items });
+                } else {
+                    //Hand-rolled retry loop, each failure means another thread won
+                    loop {
+                        let current = atom.load();
+                        let mut items = (*current).clone();
+                        items.push(thread);
+                        if atom.compare_and_set(&current, items) { break; }
+                    }
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    //No update was lost and every one was watched
+    let items = atom.load();
+    assert_eq!(items.len(), 8 * 500);
+    assert_eq!(watched.load(Ordering::SeqCst), 8 * 500);
+    for thread in 0..8 {
+        assert_eq!(items.iter().filter(|&&item| item == thread).count(), 500);
+    }
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn atom_map_threads() {
+    use map::Map;
+
+    let atom = Arc::new(Atom::new(Map::<usize, usize>::new()));
+    atom.watch(|old, new| assert_eq!(old.len() + 1, new.len()));
+
+    let threads: Vec<_> = (0..8).map(|thread| {
+        let atom = atom.clone();
+        thread::spawn(move || {
+            for i in 0..500 {
+                let key = thread * 1000 + i;
+                if i % 2 == 0 {
+                    atom.swap(|map| map.insert(key, thread).0);
+                } else {
+                    loop {
+                        let current = atom.load();
+                        if atom.compare_and_set(&current, current.insert(key, thread).0) { break; }
+                    }
+                }
+                //Snapshots taken by one thread are never disturbed by the others
+                let snapshot = atom.load();
+                let len = snapshot.len();
+                assert_eq!(snapshot.get(&key), Some(&thread));
+                assert_eq!(snapshot.iter().count(), len);
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let map = atom.load();
+    assert_eq!(map.len(), 8 * 500);
+    for thread in 0..8 {
+        assert!((0..500).all(|i| map.get(&(thread * 1000 + i)) == Some(&thread)));
+    }
+}
@@ -0,0 +1,348 @@
+//! State-based replicated data types built on `Map` and `Set`.
+//!
+//! Every type has a `merge` that is commutative, associative and idempotent, so replicas that
+//! exchange their states in any order, any number of times, end up equal. `delta(since)` extracts
+//! the part of a state that a replica known to hold `since` is missing, merging the delta has the
+//! same effect as merging the whole state. Replicas are named by any ordered `R`.
+
+use std::borrow::Borrow;
+
+use map::{DiffItem, Entry, Map};
+use set::Set;
+use tree::binary_tree::Diff;
+
+/// One add of an element to an `ORSet`: the replica that made it and that replica's add count.
+pub type Dot<R> = (R, u64);
+
+/// A counter that only grows. Each replica counts its own increments, the value is their sum.
+pub struct GCounter<R: Ord + Clone> {
+    counts: Map<R, u64>,
+}
+
+impl<R: Ord + Clone> GCounter<R> {
+    pub fn new() -> Self {
+        GCounter { counts: Map::new() }
+    }
+
+    pub fn increment(&self, replica: R, by: u64) -> Self {
+        GCounter { counts: self.counts.update(replica, |count| Some(count.unwrap_or(&0) + by)) }
+    }
+
+    pub fn value(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    /// The increments seen from `replica`.
+    pub fn get(&self, replica: &R) -> u64 {
+        self.counts.get(replica).cloned().unwrap_or(0)
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        GCounter { counts: self.counts.union_with(&other.counts, |_, ours, theirs| *ours.max(theirs)) }
+    }
+
+    pub fn delta(&self, since: &Self) -> Self {
+        let counts = since.counts.diff(&self.counts).filter_map(|change| match change {
+            DiffItem::Added(replica, count) | DiffItem::Changed(replica, _, count) => Some((replica, count)),
+            DiffItem::Removed(..) => None,
+        });
+        GCounter { counts: counts.collect() }
+    }
+}
+
+impl<R: Ord + Clone> Clone for GCounter<R> {
+    fn clone(&self) -> Self {
+        GCounter { counts: self.counts.clone() }
+    }
+}
+
+impl<R: Ord + Clone> Default for GCounter<R> {
+    fn default() -> Self {
+        GCounter::new()
+    }
+}
+
+/// A counter that goes both ways, kept as one `GCounter` of increments and one of decrements.
+pub struct PNCounter<R: Ord + Clone> {
+    up: GCounter<R>,
+    down: GCounter<R>,
+}
+
+impl<R: Ord + Clone> PNCounter<R> {
+    pub fn new() -> Self {
+        PNCounter { up: GCounter::new(), down: GCounter::new() }
+    }
+
+    pub fn increment(&self, replica: R, by: u64) -> Self {
+        PNCounter { up: self.up.increment(replica, by), down: self.down.clone() }
+    }
+
+    pub fn decrement(&self, replica: R, by: u64) -> Self {
+        PNCounter { up: self.up.clone(), down: self.down.increment(replica, by) }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.up.value() as i64 - self.down.value() as i64
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        PNCounter { up: self.up.merge(&other.up), down: self.down.merge(&other.down) }
+    }
+
+    pub fn delta(&self, since: &Self) -> Self {
+        PNCounter { up: self.up.delta(&since.up), down: self.down.delta(&since.down) }
+    }
+}
+
+impl<R: Ord + Clone> Clone for PNCounter<R> {
+    fn clone(&self) -> Self {
+        PNCounter { up: self.up.clone(), down: self.down.clone() }
+    }
+}
+
+impl<R: Ord + Clone> Default for PNCounter<R> {
+    fn default() -> Self {
+        PNCounter::new()
+    }
+}
+
+//The latest write to one key of an `LWWMap`, `None` once the key was removed
+#[derive(Clone, Debug)]
+struct Register<V, R> {
+    stamp: (u64, R),
+    val: Option<V>,
+}
+
+//Stamps name a single write, so two registers with the same stamp hold the same value
+impl<V, R: PartialEq> PartialEq for Register<V, R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.stamp == other.stamp
+    }
+}
+
+/// A map where every key is a last-writer-wins register. Writes are stamped with a time and the
+/// writing replica, the replica breaks ties so stamps are unique as long as each replica's times
+/// never repeat. Removes are writes too and leave a tombstone behind.
+pub struct LWWMap<K: Ord + Clone, V: Clone, R: Ord + Clone> {
+    entries: Map<K, Register<V, R>>,
+}
+
+impl<K: Ord + Clone, V: Clone, R: Ord + Clone> LWWMap<K, V, R> {
+    pub fn new() -> Self {
+        LWWMap { entries: Map::new() }
+    }
+
+    /// Sets `key` to `val` unless the key holds a write with a later stamp.
+    pub fn put(&self, key: K, val: V, time: u64, replica: R) -> Self {
+        self.write(key, Register { stamp: (time, replica), val: Some(val) })
+    }
+
+    /// Removes `key` unless the key holds a write with a later stamp.
+    pub fn remove(&self, key: K, time: u64, replica: R) -> Self {
+        self.write(key, Register { stamp: (time, replica), val: None })
+    }
+
+    fn write(&self, key: K, register: Register<V, R>) -> Self {
+        let entries = self.entries.update(key, |old| match old {
+            Some(old) if old.stamp > register.stamp => Some(old.clone()),
+            _ => Some(register),
+        });
+        LWWMap { entries }
+    }
+
+    pub fn get<Q: ?Sized + Ord>(&self, key: &Q) -> Option<&V> where K: Borrow<Q> {
+        self.entries.get(key).and_then(|register| register.val.as_ref())
+    }
+
+    /// The live entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item=(&K, &V)> {
+        self.entries.iter().filter_map(|(key, register)| register.val.as_ref().map(|val| (key, val)))
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let entries = self.entries.union_with(&other.entries, |_, ours, theirs| {
+            if theirs.stamp > ours.stamp { theirs.clone() } else { ours.clone() }
+        });
+        LWWMap { entries }
+    }
+
+    pub fn delta(&self, since: &Self) -> Self {
+        let entries = since.entries.diff(&self.entries).filter_map(|change| match change {
+            DiffItem::Added(key, register) | DiffItem::Changed(key, _, register) => Some((key, register)),
+            DiffItem::Removed(..) => None,
+        });
+        LWWMap { entries: entries.collect() }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, R: Ord + Clone> Clone for LWWMap<K, V, R> {
+    fn clone(&self) -> Self {
+        LWWMap { entries: self.entries.clone() }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, R: Ord + Clone> Default for LWWMap<K, V, R> {
+    fn default() -> Self {
+        LWWMap::new()
+    }
+}
+
+/// An observed-remove set. Every add is tagged with a fresh `Dot`, a remove tombstones the dots it
+/// has seen for the element, so an add made concurrently with a remove survives the merge.
+pub struct ORSet<E: Ord + Clone, R: Ord + Clone> {
+    entries: Map<E, Set<Dot<R>>>,
+    tombstones: Set<Dot<R>>,
+    clock: GCounter<R>,
+}
+
+impl<E: Ord + Clone, R: Ord + Clone> ORSet<E, R> {
+    pub fn new() -> Self {
+        ORSet { entries: Map::new(), tombstones: Set::new(), clock: GCounter::new() }
+    }
+
+    pub fn add(&self, elem: E, replica: R) -> Self {
+        let clock = self.clock.increment(replica.clone(), 1);
+        let dot = (replica.clone(), clock.get(&replica));
+        let entries = self.entries.update(elem, |dots| Some(dots.cloned().unwrap_or_default().insert(dot)));
+        ORSet { entries, tombstones: self.tombstones.clone(), clock }
+    }
+
+    pub fn remove<Q: ?Sized + Ord>(&self, elem: &Q) -> Self where E: Borrow<Q> {
+        match self.entries.remove(elem) {
+            (entries, Some(dots)) => ORSet { entries, tombstones: &self.tombstones | &dots, clock: self.clock.clone() },
+            (_, None) => self.clone(),
+        }
+    }
+
+    pub fn contains<Q: ?Sized + Ord>(&self, elem: &Q) -> bool where E: Borrow<Q> {
+        self.entries.contains_key(elem)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=&E> {
+        self.entries.keys()
+    }
+
+    pub fn merge(&self, other: &Self) -> Self {
+        let tombstones = &self.tombstones | &other.tombstones;
+        let mut entries = self.entries.union_with(&other.entries, |_, ours, theirs| ours | theirs);
+        //Each side is already clear of its own tombstones, only dots removed on the other side can be left
+        if tombstones.len() != self.tombstones.len() || tombstones.len() != other.tombstones.len() {
+            entries = entries.filter_map(|_, dots| Some(dots - &tombstones).filter(|live| !live.is_empty()));
+        }
+        ORSet { entries, tombstones, clock: self.clock.merge(&other.clock) }
+    }
+
+    pub fn delta(&self, since: &Self) -> Self {
+        let same_dots = |old: &Entry<E, Set<Dot<R>>>, new: &Entry<E, Set<Dot<R>>>| {
+            old.value().len() == new.value().len() && old.value().is_subset(new.value())
+        };
+        let by_key = |a: &Entry<E, Set<Dot<R>>>, b: &Entry<E, Set<Dot<R>>>| a.key().cmp(b.key());
+        let entries = since.entries.as_tree().diff_by(self.entries.as_tree(), by_key, same_dots)
+            .filter_map(|change| match change {
+                Diff::Added(entry) | Diff::Changed(_, entry) => Some(entry),
+                Diff::Removed(_) => None,
+            })
+            .collect();
+        ORSet { entries, tombstones: &self.tombstones - &since.tombstones, clock: self.clock.delta(&since.clock) }
+    }
+}
+
+impl<E: Ord + Clone, R: Ord + Clone> Clone for ORSet<E, R> {
+    fn clone(&self) -> Self {
+        ORSet { entries: self.entries.clone(), tombstones: self.tombstones.clone(), clock: self.clock.clone() }
+    }
+}
+
+impl<E: Ord + Clone, R: Ord + Clone> Default for ORSet<E, R> {
+    fn default() -> Self {
+        ORSet::new()
+    }
+}
+
+#[cfg(test)]
+const ORDERS: [[usize; 3]; 6] = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+
+//The three states merged in every order, grouped both ways and with one state merged twice
+#[cfg(test)]
+fn merge_in_all_orders<T, F: Fn(&T, &T) -> T>(states: &[T; 3], merge: F) -> Vec<T> {
+    ORDERS.iter().flat_map(|&[a, b, c]| {
+        let left = merge(&merge(&states[a], &states[b]), &states[c]);
+        let right = merge(&states[a], &merge(&states[b], &states[c]));
+        let again = merge(&merge(&left, &states[b]), &left);
+        vec![left, right, again]
+    }).collect()
+}
+
+#[test]
+fn crdt_counters() {
+    let start = PNCounter::new().increment("a", 5);
+    let a = start.decrement("a", 2);
+    let b = start.increment("b", 10).decrement("b", 1);
+    let c = start.decrement("c", 4).increment("c", 1);
+
+    for merged in merge_in_all_orders(&[a.clone(), b.clone(), c], PNCounter::merge) {
+        assert_eq!(merged.value(), 5 - 2 + 10 - 1 - 4 + 1);
+        assert_eq!((merged.up.get(&"a"), merged.down.get(&"c")), (5, 4));
+    }
+
+    //A replica that has `start` only needs the delta, which leaves out what it already has
+    let later = a.increment("a", 3);
+    let delta = later.delta(&start);
+    assert_eq!((delta.up.value(), delta.down.value()), (8, 2));
+    assert_eq!(b.merge(&delta).value(), b.merge(&later).value());
+    assert_eq!(later.delta(&later).value(), 0);
+    assert_eq!(GCounter::new().increment(1, 2).merge(&GCounter::new().increment(2, 3)).value(), 5);
+}
+
+#[test]
+fn crdt_lww_map() {
+    let start = LWWMap::new().put("x", 0, 1, 'a').put("y", 0, 1, 'a');
+    let a = start.put("x", 1, 2, 'a').put("z", 1, 2, 'a');
+    let b = start.put("x", 2, 3, 'b').remove("y", 2, 'b');
+    //Same time as a's write to "z", the larger replica wins
+    let c = start.put("z", 3, 2, 'c').put("y", 3, 1, 'c');
+
+    for merged in merge_in_all_orders(&[a.clone(), b.clone(), c], LWWMap::merge) {
+        assert_eq!(merged.iter().collect::<Vec<_>>(), vec![(&"x", &2), (&"z", &3)]);
+    }
+
+    //Stale local writes lose too
+    assert_eq!(b.put("x", 9, 1, 'b').get("x"), Some(&2));
+
+    let later = a.put("w", 1, 5, 'a').remove("z", 5, 'a');
+    let delta = later.delta(&a);
+    assert_eq!(delta.entries.len(), 2);
+    assert!(b.merge(&a).merge(&delta).iter().eq(b.merge(&later).iter()));
+}
+
+#[test]
+fn crdt_or_set() {
+    let start = ORSet::new().add("x", 1).add("y", 1);
+    //2 removes the "x" it saw while 3 adds it again concurrently, so the new add survives
+    let a = start.remove("y").add("z", 1);
+    let b = start.remove("x").add("w", 2);
+    let c = start.add("x", 3).remove("w");
+
+    for merged in merge_in_all_orders(&[a.clone(), b.clone(), c.clone()], ORSet::merge) {
+        assert_eq!(merged.iter().collect::<Vec<_>>(), vec![&"w", &"x", &"z"]);
+        assert_eq!(merged.entries["x"].iter().collect::<Vec<_>>(), vec![&(3, 1)]);
+    }
+
+    //Removing after everything was seen removes every add
+    let seen = a.merge(&b).merge(&c).remove("x");
+    assert!(!seen.merge(&c).contains("x"));
+
+    let later = b.add("v", 2).remove("w");
+    let delta = later.delta(&b);
+    assert_eq!((delta.len(), delta.tombstones.len()), (1, 1));
+    assert!(c.merge(&b).merge(&delta).iter().eq(c.merge(&later).iter()));
+    assert!(later.delta(&later).is_empty());
+}
@@ -0,0 +1,257 @@
+//! Undo, redo and branching over versions of a persistent value.
+//!
+//! Versions form a tree. Committing after an undo starts a new branch instead of discarding the
+//! undone versions. Each version holds its own value, so storage stays small only because the
+//! values share structure, the `Map`, `Set` and `List` in this crate are cheap to keep around.
+
+use std::collections::{HashMap, VecDeque};
+
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct VersionId(usize);
+
+/// Which versions survive a commit. The current version is never pruned, and pruned versions
+/// leave their children to their nearest surviving ancestor, so undo skips over them.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Retention {
+    All,
+    //The most recent n commits
+    Last(usize),
+    //The most recent n commits and every labeled version
+    LabeledAndLast(usize),
+}
+
+struct Version<T> {
+    value: T,
+    label: Option<String>,
+    parent: Option<VersionId>,
+    children: Vec<VersionId>,
+    //The child redo moves to, the one most recently committed or undone from
+    redo: Option<VersionId>,
+}
+
+pub struct History<T> {
+    versions: HashMap<VersionId, Version<T>>,
+    //The surviving versions in commit order, so pruning looks at them and not at everything ever committed
+    order: VecDeque<VersionId>,
+    next: usize,
+    current: VersionId,
+    retention: Retention,
+}
+
+impl<T> History<T> {
+    pub fn new(initial: T) -> Self {
+        History::with_retention(initial, Retention::All)
+    }
+
+    pub fn with_retention(initial: T, retention: Retention) -> Self {
+        let root = Version { value: initial, label: None, parent: None, children: vec![], redo: None };
+        History {
+            versions: vec![(VersionId(0), root)].into_iter().collect(),
+            order: vec![VersionId(0)].into_iter().collect(),
+            next: 1,
+            current: VersionId(0),
+            retention,
+        }
+    }
+
+    pub fn current(&self) -> &T {
+        &self.version(self.current).value
+    }
+
+    pub fn current_id(&self) -> VersionId {
+        self.current
+    }
+
+    /// Records `value` as a child of the current version and moves to it.
+    pub fn commit(&mut self, value: T, label: Option<&str>) -> VersionId {
+        let id = VersionId(self.next);
+        self.next += 1;
+        let parent = self.current;
+        self.versions.insert(id, Version {
+            value,
+            label: label.map(String::from),
+            parent: Some(parent),
+            children: vec![],
+            redo: None,
+        });
+        self.order.push_back(id);
+        let parent = self.version_mut(parent);
+        parent.children.push(id);
+        parent.redo = Some(id);
+        self.current = id;
+        self.prune();
+        id
+    }
+
+    /// Moves to the parent of the current version.
+    pub fn undo(&mut self) -> Option<&T> {
+        let from = self.current;
+        let parent = self.version(from).parent?;
+        self.version_mut(parent).redo = Some(from);
+        self.current = parent;
+        Some(self.current())
+    }
+
+    /// Moves back to the child the last undo left, or the most recent commit on this version.
+    pub fn redo(&mut self) -> Option<&T> {
+        self.current = self.version(self.current).redo?;
+        Some(self.current())
+    }
+
+    /// Moves to any version that has not been pruned.
+    pub fn checkout(&mut self, id: VersionId) -> Option<&T> {
+        self.get(id)?;
+        self.current = id;
+        self.get(id)
+    }
+
+    pub fn get(&self, id: VersionId) -> Option<&T> {
+        self.versions.get(&id).map(|version| &version.value)
+    }
+
+    pub fn label(&self, id: VersionId) -> Option<&str> {
+        self.versions.get(&id).and_then(|version| version.label.as_deref())
+    }
+
+    /// The most recent version with `label`.
+    pub fn find(&self, label: &str) -> Option<VersionId> {
+        self.ids().rev().find(|&id| self.label(id) == Some(label))
+    }
+
+    pub fn parent(&self, id: VersionId) -> Option<VersionId> {
+        self.versions.get(&id).and_then(|version| version.parent)
+    }
+
+    /// The branches leaving `id`, oldest first.
+    pub fn children(&self, id: VersionId) -> &[VersionId] {
+        self.versions.get(&id).map_or(&[], |version| &version.children)
+    }
+
+    /// Every surviving version, oldest first.
+    pub fn ids(&self) -> impl DoubleEndedIterator<Item=VersionId> + '_ {
+        self.order.iter().cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    //There is always a current version
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    pub fn set_retention(&mut self, retention: Retention) {
+        self.retention = retention;
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        let (last, keep_labeled) = match self.retention {
+            Retention::All => return,
+            Retention::Last(n) => (n, false),
+            Retention::LabeledAndLast(n) => (n, true),
+        };
+        //Only versions older than the most recent `last` are candidates, oldest first
+        let mut kept = VecDeque::new();
+        for _ in 0..self.order.len().saturating_sub(last) {
+            let id = self.order.pop_front().expect("candidates are counted from the order");
+            let version = &self.versions[&id];
+            if id == self.current || (keep_labeled && version.label.is_some()) {
+                kept.push_back(id);
+                continue;
+            }
+            let version = self.versions.remove(&id).expect("the order holds surviving versions");
+            if let Some(parent) = version.parent {
+                let parent = self.version_mut(parent);
+                parent.children.retain(|&child| child != id);
+                parent.children.extend(&version.children);
+                parent.children.sort();
+                if parent.redo == Some(id) { parent.redo = version.redo; }
+            }
+            for child in version.children {
+                self.version_mut(child).parent = version.parent;
+            }
+        }
+        if !kept.is_empty() {
+            kept.append(&mut self.order);
+            self.order = kept;
+        }
+    }
+
+    fn version(&self, id: VersionId) -> &Version<T> {
+        self.versions.get(&id).expect("VersionId refers to a pruned version")
+    }
+
+    fn version_mut(&mut self, id: VersionId) -> &mut Version<T> {
+        self.versions.get_mut(&id).expect("VersionId refers to a pruned version")
+    }
+}
+
+#[test]
+fn history_undo_redo_branches() {
+    use map::Map;
+
+    let mut history = History::new(Map::<&str, i32>::new());
+    let one = history.commit(history.current().insert("a", 1).0, Some("one"));
+    let two = history.commit(history.current().insert("b", 2).0, None);
+
+    assert_eq!(history.undo().map(|map| map.len()), Some(1));
+    assert_eq!(history.current_id(), one);
+    assert_eq!(history.redo().map(|map| map.len()), Some(2));
+    assert!(history.redo().is_none());
+
+    //Committing after an undo branches, the undone version is still there
+    history.undo();
+    let other = history.commit(history.current().insert("c", 3).0, Some("other"));
+    assert_eq!(history.children(one), &[two, other]);
+    assert_eq!(history.current().keys().collect::<Vec<_>>(), vec![&"a", &"c"]);
+    history.undo();
+    assert_eq!(history.redo().map(|map| map.contains_key("c")), Some(true));
+
+    assert!(history.checkout(two).unwrap().contains_key("b"));
+    assert_eq!(history.parent(two), Some(one));
+    assert_eq!(history.find("other"), Some(other));
+    assert_eq!(history.label(one), Some("one"));
+    history.undo();
+    history.undo();
+    assert!(history.current().is_empty());
+    assert!(history.undo().is_none());
+}
+
+#[test]
+fn history_retention() {
+    let mut history = History::with_retention(0, Retention::Last(3));
+    for value in 1..10 {
+        history.commit(value, if value == 2 { Some("two") } else { None });
+    }
+    assert_eq!(history.len(), 3);
+    assert_eq!(history.undo(), Some(&8));
+    assert_eq!(history.undo(), Some(&7));
+    assert_eq!(history.undo(), None);
+    assert_eq!(history.redo(), Some(&8));
+
+    let mut history = History::with_retention(0, Retention::LabeledAndLast(2));
+    for value in 1..10 {
+        history.commit(value, if value == 2 { Some("two") } else { None });
+    }
+    let two = history.find("two").unwrap();
+    assert_eq!(history.ids().map(|id| *history.get(id).unwrap()).collect::<Vec<_>>(), vec![2, 8, 9]);
+    assert_eq!(history.undo(), Some(&8));
+    assert_eq!(history.undo(), Some(&2));
+    assert_eq!(history.children(two).len(), 1);
+    assert_eq!(history.checkout(VersionId(1)), None);
+
+    //Pruning never takes the version being worked on, redo skips the pruned 8
+    history.checkout(two);
+    history.set_retention(Retention::Last(1));
+    assert_eq!(history.current(), &2);
+    assert_eq!(history.redo(), Some(&9));
+    assert_eq!(history.undo(), Some(&2));
+
+    //Long histories keep only what survives
+    for value in 10..20_000 {
+        history.commit(value, None);
+    }
+    assert_eq!(history.ids().map(|id| *history.get(id).unwrap()).collect::<Vec<_>>(), vec![19_999]);
+}
@@ -0,0 +1,212 @@
+use std::cmp::{ PartialEq, Eq, Ord, PartialOrd, Ordering };
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+
+use tree::binary_tree::BinaryTree;
+use tree::measure::{Measure, Max};
+
+//Entries are ordered by start then end, so two entries are equal only when their intervals are.
+//An entry holds every value inserted for its interval, oldest first.
+#[derive(Clone)]
+pub struct IntervalEntry<T: Ord, V> {
+    interval: Range<T>,
+    vals: Vec<V>,
+}
+
+impl<T: Ord, V> IntervalEntry<T, V> {
+    fn cmp_interval(&self, other: &Range<T>) -> Ordering {
+        self.interval.start.cmp(&other.start)
+            .then_with(|| self.interval.end.cmp(&other.end))
+    }
+}
+
+impl<T: Ord, V> PartialEq for IntervalEntry<T, V> {
+    fn eq(&self, other: &Self) -> bool { self.cmp_interval(&other.interval) == Ordering::Equal }
+}
+impl<T: Ord, V> Eq for IntervalEntry<T, V> {}
+
+impl<T: Ord, V> PartialOrd for IntervalEntry<T, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+impl<T: Ord, V> Ord for IntervalEntry<T, V> {
+    fn cmp(&self, other: &Self) -> Ordering { self.cmp_interval(&other.interval) }
+}
+
+//Every node caches the largest end point in its subtree, which lets queries skip any subtree
+//that ends before the query begins
+impl<T: Ord + Clone, V> Measure<IntervalEntry<T, V>> for Max<T> {
+    fn identity() -> Self { <Max<T> as Measure<T>>::identity() }
+    fn measure(entry: &IntervalEntry<T, V>) -> Self { <Max<T> as Measure<T>>::measure(&entry.interval.end) }
+    fn combine(&self, other: &Self) -> Self { <Max<T> as Measure<T>>::combine(self, other) }
+}
+
+/// An interval passed to `IntervalTree::insert` ends before it starts.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct ReversedInterval<T>(pub Range<T>);
+
+impl<T: fmt::Debug> fmt::Display for ReversedInterval<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "interval {:?} ends before it starts", self.0)
+    }
+}
+
+impl<T: fmt::Debug> Error for ReversedInterval<T> {}
+
+/// A persistent multimap from half open intervals to values that answers overlap queries
+/// in O(log n + k) for k results. Values inserted for the same interval are all kept, like two
+/// events in one calendar slot, and queries return them in insertion order.
+#[derive(Clone)]
+pub struct IntervalTree<T: Ord + Clone, V: Clone> {
+    tree: BinaryTree<IntervalEntry<T, V>, Max<T>>,
+    //Values, which can be more than the tree's intervals
+    len: usize,
+}
+
+impl<T: Ord + Clone, V: Clone> IntervalTree<T, V> {
+    pub fn new() -> Self {
+        IntervalTree {
+            tree: BinaryTree::empty(),
+            len: 0,
+        }
+    }
+
+    /// The number of values stored, counting each value of a shared interval.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Adds `val` for `interval`, after any values the interval already has.
+    pub fn insert(&self, interval: Range<T>, val: V) -> Result<Self, ReversedInterval<T>> {
+        if interval.start > interval.end {
+            return Err(ReversedInterval(interval));
+        }
+        let mut vals = self.tree.find_by(&interval, &IntervalEntry::cmp_interval).map_or(vec![], |entry| entry.vals.clone());
+        vals.push(val);
+        Ok(IntervalTree { tree: self.tree.insert(IntervalEntry { interval, vals }), len: self.len + 1 })
+    }
+
+    /// Removes `interval` with all of its values.
+    pub fn remove(&self, interval: &Range<T>) -> Self {
+        match self.tree.split_by(interval, &IntervalEntry::cmp_interval) {
+            (_, None, _) => self.clone(),
+            (left, Some(entry), right) => IntervalTree { tree: BinaryTree::concat(left, right), len: self.len - entry.vals.len() },
+        }
+    }
+
+    /// Intervals that contain `point`, ordered by start.
+    pub fn overlapping_point(&self, point: &T) -> Vec<(&Range<T>, &V)> {
+        let mut found = vec![];
+        IntervalTree::collect(&self.tree, &mut found, &|end| end > point, &|start| start <= point, &|interval| {
+            interval.start <= *point && *point < interval.end
+        });
+        found
+    }
+
+    /// Intervals that share at least one point with `range`, ordered by start.
+    pub fn overlapping(&self, range: &Range<T>) -> Vec<(&Range<T>, &V)> {
+        let mut found = vec![];
+        IntervalTree::collect(&self.tree, &mut found, &|end| end > &range.start, &|start| start < &range.end, &|interval| {
+            interval.start < range.end && range.start < interval.end
+        });
+        found
+    }
+
+    /// Intervals that cover all of `range`, ordered by start.
+    pub fn containing(&self, range: &Range<T>) -> Vec<(&Range<T>, &V)> {
+        let mut found = vec![];
+        IntervalTree::collect(&self.tree, &mut found, &|end| end >= &range.end, &|start| start <= &range.start, &|interval| {
+            interval.start <= range.start && range.end <= interval.end
+        });
+        found
+    }
+
+    //In order walk that skips subtrees whose largest end fails `end_ok`
+    //and stops going right once a start fails `start_ok`
+    fn collect<'a>(tree: &'a BinaryTree<IntervalEntry<T, V>, Max<T>>,
+                   found: &mut Vec<(&'a Range<T>, &'a V)>,
+                   end_ok: &dyn Fn(&T) -> bool,
+                   start_ok: &dyn Fn(&T) -> bool,
+                   matches: &dyn Fn(&Range<T>) -> bool) {
+        match *tree {
+            BinaryTree::Empty => {},
+            BinaryTree::Node(_, ref entry, ref left, ref right, Max(ref max_end), _, _) => {
+                if !max_end.as_ref().is_some_and(end_ok) {
+                    return;
+                }
+                IntervalTree::collect(left, found, end_ok, start_ok, matches);
+                if !start_ok(&entry.interval.start) {
+                    return;
+                }
+                if matches(&entry.interval) {
+                    found.extend(entry.vals.iter().map(|val| (&entry.interval, val)));
+                }
+                IntervalTree::collect(right, found, end_ok, start_ok, matches);
+            }
+        }
+    }
+}
+
+impl<T: Ord + Clone, V: Clone> Default for IntervalTree<T, V> {
+    fn default() -> Self {
+        IntervalTree::new()
+    }
+}
+
+#[test]
+fn interval_tree_queries() {
+    let intervals: Vec<Range<i32>> = (0..60).map(|i| (i * 7) % 50..(i * 7) % 50 + i % 9).collect();
+    let tree = intervals.iter().cloned().fold(IntervalTree::new(), |tree, interval| {
+        let start = interval.start;
+        tree.insert(interval, start).unwrap()
+    });
+
+    //Repeated intervals are kept, once for each insert
+    let mut expected: Vec<Range<i32>> = intervals.clone();
+    expected.sort_by_key(|r| (r.start, r.end));
+    assert_eq!(tree.len(), expected.len());
+
+    for point in -1..60 {
+        let found: Vec<Range<i32>> = tree.overlapping_point(&point).into_iter().map(|(r, _)| r.clone()).collect();
+        let brute: Vec<Range<i32>> = expected.iter().filter(|r| r.start <= point && point < r.end).cloned().collect();
+        assert_eq!(found, brute);
+    }
+
+    for query in [0..1, 10..12, 20..40, 48..100, 5..5].iter() {
+        let found: Vec<Range<i32>> = tree.overlapping(query).into_iter().map(|(r, _)| r.clone()).collect();
+        let brute: Vec<Range<i32>> = expected.iter()
+            .filter(|r| r.start < query.end && query.start < r.end).cloned().collect();
+        assert_eq!(found, brute);
+
+        let found: Vec<Range<i32>> = tree.containing(query).into_iter().map(|(r, _)| r.clone()).collect();
+        let brute: Vec<Range<i32>> = expected.iter()
+            .filter(|r| r.start <= query.start && query.end <= r.end).cloned().collect();
+        assert_eq!(found, brute);
+    }
+}
+
+#[test]
+fn interval_tree_persistence() {
+    let calendar = IntervalTree::new()
+        .insert(9..10, "standup").unwrap()
+        .insert(13..15, "review").unwrap();
+    let rescheduled = calendar.remove(&(13..15)).insert(14..16, "review").unwrap();
+
+    assert_eq!(calendar.overlapping_point(&13), vec![(&(13..15), &"review")]);
+    assert!(rescheduled.overlapping_point(&13).is_empty());
+    assert_eq!(rescheduled.overlapping(&(15..20)), vec![(&(14..16), &"review")]);
+    assert_eq!(calendar.len(), 2);
+    assert_eq!(calendar.remove(&(0..1)).len(), 2);
+
+    //Two events in one slot are both kept, a reversed slot is refused
+    let busy = calendar.insert(13..15, "interview").unwrap();
+    assert_eq!(busy.overlapping_point(&14), vec![(&(13..15), &"review"), (&(13..15), &"interview")]);
+    assert_eq!(busy.len(), 3);
+    assert_eq!(busy.remove(&(13..15)).len(), 1);
+    let backwards = Range { start: 15, end: 13 };
+    assert_eq!(busy.insert(backwards.clone(), "backwards").err(), Some(ReversedInterval(backwards)));
+}
@@ -1,10 +1,31 @@
 
+#[cfg(feature = "serde")]
+extern crate serde;
+
+//Nodes are shared through `Rc`, or through `Arc` with the `sync` feature so collections can cross threads
+#[cfg(not(feature = "sync"))]
+use std::rc::Rc;
+#[cfg(feature = "sync")]
+use std::sync::Arc as Rc;
+
 #[macro_use]
 pub mod list;
 #[macro_use]
 pub mod tree;
 #[macro_use]
 pub mod map;
+#[macro_use]
+pub mod set;
+pub mod interval_tree;
+pub mod snapshot;
+pub mod history;
+pub mod crdt;
+pub mod patch;
+pub mod atom;
+pub mod stm;
+pub mod nested;
+#[cfg(feature = "serde")]
+pub mod serialization;
 
 
 // #[test]
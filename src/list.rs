@@ -3,7 +3,7 @@ use std::borrow::Borrow;
 use std::convert::Into;
 use std::iter::{Iterator, IntoIterator, FromIterator};
 use std::ops::Deref;
-use std::rc::Rc;
+use Rc;
 use std::vec::IntoIter;
 
 #[macro_escape]
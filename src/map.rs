@@ -1,86 +1,545 @@
-use std::cmp::{ PartialEq, Eq, Ord, PartialOrd, Ordering };
+use std::cmp::Ordering;
 use std::iter::{Iterator, IntoIterator, FromIterator};
+use std::borrow::Borrow;
+use std::ops::{Add, BitAnd, BitOr, BitXor, Index, RangeBounds, Sub};
 //use std::hash::{ Hash, Hasher };
 
-use tree::binary_tree::{BinaryTree, Iter};
+use patch::{Op, Patch, PatchError};
+use tree::binary_tree::{BinaryTree, Diff, Iter, Only, SortedError};
+use tree::comparator::{Comparator, OrdComparator};
+use tree::measure::{Measure, Sum, Max};
+
+/// Builds a `Map` from `key => value` pairs, later pairs replace earlier ones with the same key.
+#[macro_export]
+macro_rules! map {
+    {} => {<$crate::map::Map<_, _>>::new()};
+    {$($key:expr => $val:expr),+ $(,)*} => {{
+        let map = <$crate::map::Map<_, _>>::new();
+        $(
+            let map = map.put($key, $val);
+        )+
+        map
+    }};
+}
 
 #[derive(Clone)]
-pub struct Entry<K: Eq + Ord, V> {
+pub struct Entry<K, V> {
     key: K,
     val: V,
 }
 
-impl<K: Ord + Eq, V> PartialEq for Entry<K, V> {
-    fn eq(&self, other: &Self) -> bool { self.key == other.key }
-    fn ne(&self, other: &Self) -> bool { self.key != other.key }
-}
-impl<K: Ord + Eq, V> PartialEq<K> for Entry<K, V> {
-    fn eq(&self, other: &K) -> bool { self.key == *other }
-    fn ne(&self, other: &K) -> bool { self.key != *other }
-}
-impl<K: Ord + Eq, V> Eq for Entry<K, V> {}
-
-impl<K: Ord + Eq, V> PartialOrd for Entry<K, V> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { self.key.partial_cmp(&other.key) }
-    fn lt(&self, other: &Self) -> bool { self.key < other.key }
-    fn le(&self, other: &Self) -> bool { self.key <= other.key }
-    fn gt(&self, other: &Self) -> bool { self.key > other.key }
-    fn ge(&self, other: &Self) -> bool { self.key >= other.key }
-}
-impl<K: Ord + Eq, V> PartialOrd<K> for Entry<K, V> {
-    fn partial_cmp(&self, other: &K) -> Option<Ordering> { self.key.partial_cmp(other) }
-    fn lt(&self, other: &K) -> bool { self.key < *other }
-    fn le(&self, other: &K) -> bool { self.key <= *other }
-    fn gt(&self, other: &K) -> bool { self.key > *other }
-    fn ge(&self, other: &K) -> bool { self.key >= *other }
-}
-
-impl<K: Eq + Ord, V> Entry<K, V> {
+impl<K, V> Entry<K, V> {
     pub fn new(key: K, val: V) -> Self {
         Entry {
             key: key,
             val: val
         }
     }
+
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    pub fn value(&self) -> &V {
+        &self.val
+    }
+
+    fn pair(&self) -> (&K, &V) {
+        (&self.key, &self.val)
+    }
+}
+
+/// Orders map entries by their keys alone, using `C`.
+#[derive(Clone, Copy, Default)]
+pub struct KeyComparator<C = OrdComparator>(pub C);
+
+impl<K, V, C: Comparator<K>> Comparator<Entry<K, V>> for KeyComparator<C> {
+    #[inline]
+    fn compare(&self, a: &Entry<K, V>, b: &Entry<K, V>) -> Ordering {
+        self.0.compare(&a.key, &b.key)
+    }
+}
+
+//Measures over a map see whole entries, Sum and Max measure the values as they would on their own
+impl<K, V> Measure<Entry<K, V>> for Sum<V> where V: Add<Output=V> + Default + Clone {
+    fn identity() -> Self { <Sum<V> as Measure<V>>::identity() }
+    fn measure(entry: &Entry<K, V>) -> Self { <Sum<V> as Measure<V>>::measure(&entry.val) }
+    fn combine(&self, other: &Self) -> Self { <Sum<V> as Measure<V>>::combine(self, other) }
+}
+
+impl<K, V> Measure<Entry<K, V>> for Max<V> where V: Ord + Clone {
+    fn identity() -> Self { <Max<V> as Measure<V>>::identity() }
+    fn measure(entry: &Entry<K, V>) -> Self { <Max<V> as Measure<V>>::measure(&entry.val) }
+    fn combine(&self, other: &Self) -> Self { <Max<V> as Measure<V>>::combine(self, other) }
+}
+
+/// One difference between two maps, see `Map::diff`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum DiffItem<K, V> {
+    Added(K, V),
+    Removed(K, V),
+    //The key with its old and new value
+    Changed(K, V, V),
 }
-// impl<K: Ord + Eq, V> PartialOrd<Entry<K, V>> for K {
-//     fn partial_cmp(&self, other: &Entry<K, V>) -> Option<Ordering> { *self.partial_cmp(other.key) }
-//     fn lt(&self, other: &Entry<K, V>) -> bool { *self < other.key }
-//     fn le(&self, other: &Entry<K, V>) -> bool { *self <= other.key }
-//     fn gt(&self, other: &Entry<K, V>) -> bool { *self > other.key }
-//     fn ge(&self, other: &Entry<K, V>) -> bool { *self >= other.key }
-// }
 
-impl<K: Ord + Eq, V> Ord for Entry<K, V> {
-    fn cmp(&self, other: &Self) -> Ordering { self.key.cmp(&other.key) }
+/// A key that both sides of `Map::merge3` changed in different ways, `None` where the key is absent.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Conflict<K, V> {
+    pub key: K,
+    pub base: Option<V>,
+    pub ours: Option<V>,
+    pub theirs: Option<V>,
 }
-/*impl<K: Ord + Eq, V: Eq> Ord<K> for Entry<K, V> {
-    fn cmp(&self, other: &K) -> Ordering { self.key.cmp(other) }
-}*/
 
-pub struct Map<K: Clone + Ord + Eq, V: Clone> {
-    tree: BinaryTree<Entry<K, V>>,
+//Names the callback type of merges that keep or drop one-sided entries without calling anything
+type EntryFn<K, V> = fn(&Entry<K, V>) -> Option<Entry<K, V>>;
+
+/// A persistent sorted map. Keys are ordered by the comparator `C`, which defaults to `Ord`.
+/// Every map derived from this one keeps a clone of its comparator, so the comparator should be
+/// cheap to clone. Operations on two maps use the order of `self`, both maps must use the same order.
+pub struct Map<K: Clone, V: Clone, M = (), C = OrdComparator> {
+    tree: BinaryTree<Entry<K, V>, M, KeyComparator<C>>,
+    cmp: KeyComparator<C>,
 }
 
-impl<K: Clone + Ord + Eq, V: Clone> Map<K, V> {
-    pub fn new() -> Self {
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone> Map<K, V, M, C> {
+    pub fn new() -> Self where C: Default {
+        Map::with_comparator(C::default())
+    }
+
+    /// An empty map whose keys are ordered by `cmp`.
+    pub fn with_comparator(cmp: C) -> Self {
         Map {
-            tree: BinaryTree::empty()
+            tree: BinaryTree::empty(),
+            cmp: KeyComparator(cmp),
+        }
+    }
+
+    pub fn comparator(&self) -> &C {
+        &self.cmp.0
+    }
+
+    //A map derived from this one, ordered by the same comparator
+    fn with_tree<W: Clone, N>(&self, tree: BinaryTree<Entry<K, W>, N, KeyComparator<C>>) -> Map<K, W, N, C> {
+        Map { tree, cmp: self.cmp.clone() }
+    }
+
+    #[inline]
+    fn order(&self) -> impl Fn(&Entry<K, V>, &Entry<K, V>) -> Ordering + '_ {
+        move |a: &Entry<K, V>, b: &Entry<K, V>| self.cmp.compare(a, b)
+    }
+
+    //Probes the tree with a bare key, entries only ever compare against each other
+    #[inline]
+    fn by_key<Q: ?Sized>(&self) -> impl Fn(&Entry<K, V>, &Q) -> Ordering + '_ where K: Borrow<Q>, C: Comparator<Q> {
+        move |entry: &Entry<K, V>, key: &Q| self.cmp.0.compare(entry.key.borrow(), key)
+    }
+
+    pub fn from_sorted_iter<I: IntoIterator<Item=(K, V)>>(iterator: I) -> Result<Self, SortedError> where C: Default {
+        let map = Self::new();
+        let tree = BinaryTree::from_sorted_iter_by(iterator.into_iter().map(|(k, v)| Entry::new(k, v)), &map.order())?;
+        Ok(map.with_tree(tree))
+    }
+
+    pub fn from_sorted_iter_unchecked<I: IntoIterator<Item=(K, V)>>(iterator: I) -> Self where C: Default {
+        Self::new().with_tree(BinaryTree::from_sorted_iter_unchecked(iterator.into_iter().map(|(k, v)| Entry::new(k, v))))
+    }
+
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V> where K: Borrow<Q>, C: Comparator<Q> {
+        self.tree.find_by(key, &self.by_key()).map(Entry::value)
+    }
+
+    pub fn get_key_value<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)> where K: Borrow<Q>, C: Comparator<Q> {
+        self.tree.find_by(key, &self.by_key()).map(Entry::pair)
+    }
+
+    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool where K: Borrow<Q>, C: Comparator<Q> {
+        self.tree.find_by(key, &self.by_key()).is_some()
+    }
+
+    /// Removes `key`, returning the new map and the value that was stored under it.
+    pub fn remove<Q: ?Sized>(&self, key: &Q) -> (Self, Option<V>) where K: Borrow<Q>, C: Comparator<Q> {
+        match self.tree.split_by(key, &self.by_key()) {
+            (_, None, _) => (self.clone(), None),
+            (left, Some(entry), right) => (self.with_tree(BinaryTree::concat(left, right)), Some(entry.val)),
         }
     }
 
-    pub fn get(&self, key: K) -> Option<Entry<K, V>> {
-        self.tree.get(key)
+    /// Inserts, modifies or removes `key` in a single O(log n) walk. `f` sees the current value
+    /// and returns the value to store, or `None` to remove the key.
+    pub fn update<F>(&self, key: K, f: F) -> Self where F: FnOnce(Option<&V>) -> Option<V> {
+        let tree = self.tree.update_by(&key, &self.by_key(), |found| {
+            f(found.map(Entry::value)).map(|val| Entry::new(key.clone(), val))
+        });
+        self.with_tree(tree)
+    }
+
+    /// Replaces the value of `key` with `f` of its current value. Absent keys are left alone.
+    pub fn update_with<Q: ?Sized, F>(&self, key: &Q, f: F) -> Self
+        where K: Borrow<Q>, C: Comparator<Q>, F: FnOnce(&V) -> V
+    {
+        let tree = self.tree.update_by(key, &self.by_key(), |found| {
+            found.map(|entry| Entry::new(entry.key.clone(), f(&entry.val)))
+        });
+        self.with_tree(tree)
+    }
+
+    /// Inserts `val`, or stores `f(old, val)` if `key` is already present.
+    pub fn insert_with<F>(&self, key: K, val: V, f: F) -> Self where F: FnOnce(&V, V) -> V {
+        self.update(key, |found| match found {
+            None => Some(val),
+            Some(old) => Some(f(old, val)),
+        })
+    }
+
+    /// A persistent view of `key` that builds a new map, see `MapEntry`.
+    pub fn entry(&self, key: K) -> MapEntry<'_, K, V, M, C> {
+        MapEntry { map: self, key, modify: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.length()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// The entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item=(&K, &V)> {
+        self.tree.iter().map(Entry::pair)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item=&K> {
+        self.tree.iter().map(Entry::key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item=&V> {
+        self.tree.iter().map(Entry::value)
     }
 
     pub fn put(self, key: K, val: V) -> Self {
-        Map { tree: self.tree.insert(Entry::new(key, val)) }
+        let tree = self.tree.insert_by(Entry::new(key, val), &self.order());
+        self.with_tree(tree)
+    }
+
+    /// Maps `key` to `val`, returning the new map and the value previously stored under `key`.
+    pub fn insert(&self, key: K, val: V) -> (Self, Option<V>) {
+        let (tree, old) = self.tree.insert_returning_old_by(Entry::new(key, val), &self.order());
+        (self.with_tree(tree), old.map(|entry| entry.val))
+    }
+
+    //Set operations compare keys only, entries are always taken from self where both maps hold a key
+    pub fn union(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.union_by(&other.tree, &self.order()))
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.intersection_by(&other.tree, &self.order()))
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.difference_by(&other.tree, &self.order()))
+    }
+
+    pub fn symmetric_difference(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.symmetric_difference_by(&other.tree, &self.order()))
+    }
+
+    /// Every key of both maps, keys present in both are combined with `f(key, ours, theirs)`.
+    pub fn union_with<F>(&self, other: &Self, f: F) -> Self where F: Fn(&K, &V, &V) -> V {
+        let keep: &Only<EntryFn<K, V>> = &Only::Keep;
+        let tree = self.tree.merge_with_by(&other.tree, &self.order(), keep, keep, &|ours: &Entry<K, V>, theirs: &Entry<K, V>| {
+            Some(Entry::new(ours.key.clone(), f(&ours.key, &ours.val, &theirs.val)))
+        });
+        self.with_tree(tree)
+    }
+
+    /// The keys present in both maps, with values combined by `f(key, ours, theirs)`.
+    pub fn intersection_with<F>(&self, other: &Self, f: F) -> Self where F: Fn(&K, &V, &V) -> V {
+        let drop: &Only<EntryFn<K, V>> = &Only::Drop;
+        let tree = self.tree.merge_with_by(&other.tree, &self.order(), drop, drop, &|ours: &Entry<K, V>, theirs: &Entry<K, V>| {
+            Some(Entry::new(ours.key.clone(), f(&ours.key, &ours.val, &theirs.val)))
+        });
+        self.with_tree(tree)
+    }
+
+    /// The general merge. Each callback returns the value to keep for its key, or `None` to leave it out:
+    /// `left` sees keys found only in `self`, `right` keys found only in `other` and `both` the rest.
+    pub fn merge_with<L, R, B>(&self, other: &Self, left: L, right: R, both: B) -> Self
+        where L: Fn(&K, &V) -> Option<V>, R: Fn(&K, &V) -> Option<V>, B: Fn(&K, &V, &V) -> Option<V>
+    {
+        let only = |f: &dyn Fn(&K, &V) -> Option<V>, entry: &Entry<K, V>| {
+            f(&entry.key, &entry.val).map(|val| Entry::new(entry.key.clone(), val))
+        };
+        let tree = self.tree.merge_with_by(
+            &other.tree,
+            &self.order(),
+            &Only::With(|entry: &Entry<K, V>| only(&left, entry)),
+            &Only::With(|entry: &Entry<K, V>| only(&right, entry)),
+            &|ours: &Entry<K, V>, theirs: &Entry<K, V>| {
+                both(&ours.key, &ours.val, &theirs.val).map(|val| Entry::new(ours.key.clone(), val))
+            });
+        self.with_tree(tree)
+    }
+
+    /// Applies `f` to every value. The new map has the same shape as this one and is built in O(n).
+    pub fn map_values<W, F>(&self, f: F) -> Map<K, W, M, C>
+        where W: Clone, M: Measure<Entry<K, W>>, F: Fn(&K, &V) -> W
+    {
+        self.with_tree(self.tree.map_monotone(&|entry: &Entry<K, V>| Entry::new(entry.key.clone(), f(&entry.key, &entry.val))))
+    }
+
+    /// The entries that satisfy `pred`, in O(n).
+    pub fn filter<P>(&self, pred: P) -> Self where P: Fn(&K, &V) -> bool {
+        self.with_tree(self.tree.filter(|entry| pred(&entry.key, &entry.val)))
+    }
+
+    /// The entries that satisfy `pred` and the entries that don't, in O(n).
+    pub fn partition<P>(&self, pred: P) -> (Self, Self) where P: Fn(&K, &V) -> bool {
+        let (yes, no) = self.tree.partition(|entry| pred(&entry.key, &entry.val));
+        (self.with_tree(yes), self.with_tree(no))
+    }
+
+    /// Keeps the entries for which `f` returns a value, replacing their values with it, in O(n).
+    pub fn filter_map<W, F>(&self, f: F) -> Map<K, W, M, C>
+        where W: Clone, M: Measure<Entry<K, W>>, F: Fn(&K, &V) -> Option<W>
+    {
+        //Keys are unchanged so the surviving entries are still sorted
+        let entries = self.tree.iter().filter_map(|entry| f(&entry.key, &entry.val).map(|val| Entry::new(entry.key.clone(), val)));
+        self.with_tree(BinaryTree::from_sorted_iter_unchecked(entries))
+    }
+
+    /// The entries added, removed or changed going from `self` to `other`, in key order.
+    /// Subtrees the two maps share are skipped, so diffing two versions of a map
+    /// costs about O(edits * log n). Changes are found as the iterator is advanced.
+    pub fn diff(&self, other: &Self) -> impl Iterator<Item=DiffItem<K, V>> where V: PartialEq {
+        let cmp = self.cmp.clone();
+        let order = move |a: &Entry<K, V>, b: &Entry<K, V>| cmp.compare(a, b);
+        self.tree.diff_by(&other.tree, order, |a: &Entry<K, V>, b: &Entry<K, V>| a.val == b.val)
+            .map(|change| match change {
+                Diff::Added(entry) => DiffItem::Added(entry.key, entry.val),
+                Diff::Removed(entry) => DiffItem::Removed(entry.key, entry.val),
+                Diff::Changed(old, new) => DiffItem::Changed(old.key, old.val, new.val),
+            })
+    }
+
+    /// Three-way merge of two versions derived from `base`. A change made on one side only is taken as it is,
+    /// where both sides changed a key differently `resolve` picks the merged value (`None` leaves the key out)
+    /// and the key is reported as a conflict. The merge starts from `ours` and only visits what `theirs`
+    /// changed, subtrees `theirs` still shares with `base` are skipped without being compared.
+    pub fn merge3<F>(base: &Self, ours: &Self, theirs: &Self, resolve: F) -> (Self, Vec<Conflict<K, V>>)
+        where V: PartialEq, F: Fn(&Conflict<K, V>) -> Option<V>
+    {
+        let mut merged = ours.clone();
+        let mut conflicts = vec![];
+        for change in base.diff(theirs) {
+            let (key, base_val, theirs_val) = match change {
+                DiffItem::Added(key, val) => (key, None, Some(val)),
+                DiffItem::Removed(key, val) => (key, Some(val), None),
+                DiffItem::Changed(key, old, new) => (key, Some(old), Some(new)),
+            };
+            let ours_val = ours.get(&key);
+            if ours_val == theirs_val.as_ref() {
+                continue;
+            }
+            if ours_val == base_val.as_ref() {
+                merged = merged.update(key, |_| theirs_val);
+            } else {
+                let conflict = Conflict { key, base: base_val, ours: ours_val.cloned(), theirs: theirs_val };
+                merged = merged.update(conflict.key.clone(), |_| resolve(&conflict));
+                conflicts.push(conflict);
+            }
+        }
+        (merged, conflicts)
+    }
+
+    /// Applies every operation of `patch`, failing on the first one whose expected old value
+    /// is not what this map holds.
+    pub fn apply_patch(&self, patch: &Patch<K, V>) -> Result<Self, PatchError<K>> where V: PartialEq {
+        let mut map = self.clone();
+        for op in patch.ops() {
+            let key = op.key();
+            let current = map.get(key);
+            map = match (op, current) {
+                (Op::Insert(_, val), None) => map.put(key.clone(), val.clone()),
+                (Op::Remove(_, old), Some(current)) if old == current => map.remove(key).0,
+                (Op::Replace(_, old, new), Some(current)) if old == current => map.put(key.clone(), new.clone()),
+                (Op::Insert(..), Some(_)) => return Err(PatchError::Present(key.clone())),
+                (_, None) => return Err(PatchError::Missing(key.clone())),
+                (_, Some(_)) => return Err(PatchError::Mismatch(key.clone())),
+            };
+        }
+        Ok(map)
+    }
+
+    pub fn first(&self) -> Option<(&K, &V)> {
+        self.tree.first().map(Entry::pair)
+    }
+
+    pub fn last(&self) -> Option<(&K, &V)> {
+        self.tree.last().map(Entry::pair)
+    }
+
+    pub fn pop_first(&self) -> Option<((K, V), Self)> {
+        self.tree.pop_first().map(|(entry, tree)| ((entry.key, entry.val), self.with_tree(tree)))
+    }
+
+    pub fn pop_last(&self) -> Option<((K, V), Self)> {
+        self.tree.pop_last().map(|(entry, tree)| ((entry.key, entry.val), self.with_tree(tree)))
+    }
+
+    /// The entry with the largest key less than or equal to `key`.
+    pub fn floor<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)> where K: Borrow<Q>, C: Comparator<Q> {
+        self.tree.floor_by(key, &self.by_key()).map(Entry::pair)
+    }
+
+    /// The entry with the smallest key greater than or equal to `key`.
+    pub fn ceiling<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)> where K: Borrow<Q>, C: Comparator<Q> {
+        self.tree.ceiling_by(key, &self.by_key()).map(Entry::pair)
+    }
+
+    /// The entry with the largest key strictly less than `key`.
+    pub fn predecessor<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)> where K: Borrow<Q>, C: Comparator<Q> {
+        self.tree.predecessor_by(key, &self.by_key()).map(Entry::pair)
+    }
+
+    /// The entry with the smallest key strictly greater than `key`.
+    pub fn successor<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)> where K: Borrow<Q>, C: Comparator<Q> {
+        self.tree.successor_by(key, &self.by_key()).map(Entry::pair)
+    }
+
+    /// The tree holding the entries.
+    pub fn as_tree(&self) -> &BinaryTree<Entry<K, V>, M, KeyComparator<C>> {
+        &self.tree
+    }
+
+    /// The combined measure of every entry in the map.
+    pub fn summary(&self) -> M {
+        self.tree.summary()
+    }
+
+    /// The combined measure of the entries whose keys fall in `range`, in O(log n).
+    pub fn measure_range<R: RangeBounds<K>>(&self, range: R) -> M {
+        self.tree.measure_range_by(range.start_bound(), range.end_bound(), &self.by_key())
+    }
+}
+
+impl<K, V, M, C> From<BinaryTree<Entry<K, V>, M, KeyComparator<C>>> for Map<K, V, M, C>
+    where K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone + Default
+{
+    fn from(tree: BinaryTree<Entry<K, V>, M, KeyComparator<C>>) -> Self {
+        Self::new().with_tree(tree)
+    }
+}
+
+//The operators act on key sets like the named set operations, values are taken from the left map
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone> BitOr<&Map<K, V, M, C>> for &Map<K, V, M, C> {
+    type Output = Map<K, V, M, C>;
+
+    fn bitor(self, other: &Map<K, V, M, C>) -> Map<K, V, M, C> {
+        self.union(other)
+    }
+}
+
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone> BitAnd<&Map<K, V, M, C>> for &Map<K, V, M, C> {
+    type Output = Map<K, V, M, C>;
+
+    fn bitand(self, other: &Map<K, V, M, C>) -> Map<K, V, M, C> {
+        self.intersection(other)
+    }
+}
+
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone> Sub<&Map<K, V, M, C>> for &Map<K, V, M, C> {
+    type Output = Map<K, V, M, C>;
+
+    fn sub(self, other: &Map<K, V, M, C>) -> Map<K, V, M, C> {
+        self.difference(other)
+    }
+}
+
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone> BitXor<&Map<K, V, M, C>> for &Map<K, V, M, C> {
+    type Output = Map<K, V, M, C>;
+
+    fn bitxor(self, other: &Map<K, V, M, C>) -> Map<K, V, M, C> {
+        self.symmetric_difference(other)
+    }
+}
+
+/// A key in a map, waiting to be inserted or modified. Finishing with `or_insert`,
+/// `or_insert_with` or `or_default` returns the new map, the original map is unchanged.
+pub struct MapEntry<'a, K: Clone, V: Clone, M, C, F = fn(&mut V)> {
+    map: &'a Map<K, V, M, C>,
+    key: K,
+    modify: Option<F>,
+}
+
+impl<'a, K, V, M, C, F> MapEntry<'a, K, V, M, C, F>
+    where K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone, F: FnOnce(&mut V)
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Modifies the value if the key is present. Calls are applied in order.
+    pub fn and_modify<G: FnOnce(&mut V)>(self, g: G) -> MapEntry<'a, K, V, M, C, impl FnOnce(&mut V)> {
+        let modify = self.modify;
+        MapEntry {
+            map: self.map,
+            key: self.key,
+            modify: Some(move |val: &mut V| {
+                if let Some(f) = modify { f(val); }
+                g(val);
+            }),
+        }
+    }
+
+    pub fn or_insert(self, default: V) -> Map<K, V, M, C> {
+        self.or_insert_with(|| default)
+    }
+
+    pub fn or_insert_with<D: FnOnce() -> V>(self, default: D) -> Map<K, V, M, C> {
+        let modify = self.modify;
+        self.map.update(self.key, |found| match found {
+            None => Some(default()),
+            Some(val) => {
+                let mut val = val.clone();
+                if let Some(f) = modify { f(&mut val); }
+                Some(val)
+            }
+        })
+    }
+
+    pub fn or_default(self) -> Map<K, V, M, C> where V: Default {
+        self.or_insert_with(V::default)
+    }
+}
+
+impl<K: Clone, V: Clone, M: Clone, C: Clone> Clone for Map<K, V, M, C> {
+    fn clone(&self) -> Self {
+        Map { tree: self.tree.clone(), cmp: self.cmp.clone() }
+    }
+}
+
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone + Default> Default for Map<K, V, M, C> {
+    fn default() -> Self {
+        Map::new()
+    }
+}
+
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone, Q: ?Sized> Index<&Q> for Map<K, V, M, C>
+    where K: Borrow<Q>, C: Comparator<Q>
+{
+    type Output = V;
+
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
     }
 }
 
-impl<K: Clone + Ord + Eq, V: Clone> IntoIterator for Map<K, V> {
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone> IntoIterator for Map<K, V, M, C> {
     type Item = Entry<K, V>;
-    type IntoIter = Iter<Entry<K, V>>;
+    type IntoIter = Iter<Entry<K, V>, M, KeyComparator<C>>;
 
     #[inline]
     fn into_iter(self) -> Self::IntoIter {
@@ -88,14 +547,14 @@ impl<K: Clone + Ord + Eq, V: Clone> IntoIterator for Map<K, V> {
     }
 }
 
-impl<K: Clone + Ord + Eq, V: Clone> FromIterator<Entry<K, V>> for Map<K, V> {
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone + Default> FromIterator<Entry<K, V>> for Map<K, V, M, C> {
     fn from_iter<I: IntoIterator<Item=Entry<K, V>>>(iterator: I) -> Self {
         iterator
             .into_iter()
             .fold(Map::new(), | map, Entry{key, val} | map.put(key, val))
     }
 }
-impl<K: Clone + Ord + Eq, V: Clone> FromIterator<(K, V)> for Map<K, V> {
+impl<K: Clone, V: Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone + Default> FromIterator<(K, V)> for Map<K, V, M, C> {
     fn from_iter<I: IntoIterator<Item=(K, V)>>(iterator: I) -> Self {
         iterator
             .into_iter()
@@ -106,5 +565,323 @@ impl<K: Clone + Ord + Eq, V: Clone> FromIterator<(K, V)> for Map<K, V> {
 
 #[test]
 fn map_macro() {
-    assert!(true);
+    let empty: Map<i32, i32> = map!{};
+    assert!(empty.is_empty());
+
+    let map = map!{3 => "c", 1 => "a", 2 => "b"};
+    assert_eq!(map.iter().collect::<Vec<_>>(), vec![(&1, &"a"), (&2, &"b"), (&3, &"c")]);
+
+    let map = map!{
+        "x" => 1,
+        "y" => 2,
+        "x" => 3,
+    };
+    assert_eq!((map.len(), map["x"], map["y"]), (2, 3, 2));
+}
+
+#[test]
+fn map_key_algebra() {
+    let a: Map<i32, &str> = (0..10).map(|k| (k, "a")).collect();
+    let b: Map<i32, &str> = (5..15).map(|k| (k, "b")).collect();
+
+    let union = a.union(&b);
+    assert_eq!(union.tree.length(), 15);
+    assert_eq!(union.get(&7).copied(), Some("a"));
+    assert_eq!(union.get(&12).copied(), Some("b"));
+
+    let intersection = b.intersection(&a);
+    assert_eq!(intersection.tree.length(), 5);
+    assert_eq!(intersection.get(&7).copied(), Some("b"));
+
+    assert!(a.difference(&b).get(&7).is_none());
+    assert_eq!(a.difference(&b).tree.length(), 5);
+    assert_eq!(a.symmetric_difference(&b).tree.length(), 10);
+}
+
+#[test]
+fn map_from_sorted_iter() {
+    let map: Map<i32, i32> = Map::from_sorted_iter((0..20).map(|k| (k, k * 10))).unwrap();
+    assert_eq!(map.tree.length(), 20);
+    assert_eq!(map.get(&7).copied(), Some(70));
+
+    assert!(Map::<i32, &str>::from_sorted_iter(vec![(1, "a"), (1, "b")]).is_err());
+}
+
+#[test]
+fn map_measure_range() {
+    let map: Map<i32, i32, Sum<i32>> = (0..100).map(|k| (k, k * 2)).collect();
+    assert_eq!(map.summary(), Sum(9900));
+    assert_eq!(map.measure_range(10..20), Sum(290));
+    assert_eq!(map.measure_range(..=3), Sum(12));
+
+    let map = map.put(1000, 1);
+    assert_eq!(map.measure_range(90..), Sum(1 + (90..100).map(|k| k * 2).sum::<i32>()));
+
+    let max: Map<i32, i32, Max<i32>> = (0..100).map(|k| (k, (k * 37) % 101)).collect();
+    assert_eq!(max.measure_range(0..3), Max(Some(74)));
+    assert_eq!(max.measure_range(200..), Max(None));
+}
+
+#[test]
+fn map_neighbors() {
+    let map: Map<i32, char> = vec![(10, 'a'), (20, 'b'), (30, 'c')].into_iter().collect();
+    assert_eq!(map.first(), Some((&10, &'a')));
+    assert_eq!(map.last(), Some((&30, &'c')));
+    assert_eq!(map.floor(&25), Some((&20, &'b')));
+    assert_eq!(map.ceiling(&25), Some((&30, &'c')));
+    assert_eq!(map.predecessor(&20), Some((&10, &'a')));
+    assert_eq!(map.successor(&30), None);
+
+    let names: Map<String, i32> = vec![("b".to_string(), 2), ("d".to_string(), 4)].into_iter().collect();
+    assert_eq!(names.floor("c"), Some((&"b".to_string(), &2)));
+    assert_eq!(names.successor("b"), Some((&"d".to_string(), &4)));
+
+    let ((key, val), rest) = map.pop_first().unwrap();
+    assert_eq!((key, val), (10, 'a'));
+    assert_eq!(rest.first(), Some((&20, &'b')));
+    assert_eq!(map.first(), Some((&10, &'a')));
+}
+
+#[test]
+fn map_comparator() {
+    #[derive(Clone, Default)]
+    struct CaseInsensitive;
+    impl Comparator<String> for CaseInsensitive {
+        fn compare(&self, a: &String, b: &String) -> Ordering {
+            a.to_lowercase().cmp(&b.to_lowercase())
+        }
+    }
+
+    let map: Map<String, i32, Sum<i32>, CaseInsensitive> = vec![("b", 2), ("A", 1), ("c", 3)]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    assert_eq!(map.get(&"a".to_string()).copied(), Some(1));
+    assert_eq!(map.first(), Some((&"A".to_string(), &1)));
+
+    //Keys equal under the comparator are the same key
+    let map = map.put("B".to_string(), 20);
+    assert_eq!(map.tree.length(), 3);
+    assert_eq!(map.ceiling(&"b".to_string()), Some((&"B".to_string(), &20)));
+    assert_eq!(map.measure_range("a".to_string().."C".to_string()), Sum(21));
+}
+
+#[test]
+fn map_runtime_comparator() {
+    //The order is only known once the map is built, e.g. from a user setting
+    #[derive(Clone)]
+    struct ByDigit {
+        place: u32,
+    }
+    impl Comparator<u32> for ByDigit {
+        fn compare(&self, a: &u32, b: &u32) -> Ordering {
+            let digit = |x: &u32| x / 10u32.pow(self.place) % 10;
+            digit(a).cmp(&digit(b))
+        }
+    }
+
+    let ones: Map<u32, &str, (), ByDigit> = Map::with_comparator(ByDigit { place: 0 });
+    let ones = ones.put(12, "a").put(31, "b").put(23, "c");
+    let tens: Map<u32, &str, (), ByDigit> = Map::with_comparator(ByDigit { place: 1 });
+    let tens = tens.put(12, "a").put(31, "b").put(23, "c");
+    assert_eq!(ones.keys().collect::<Vec<_>>(), vec![&31, &12, &23]);
+    assert_eq!(tens.keys().collect::<Vec<_>>(), vec![&12, &23, &31]);
+
+    //Derived maps keep the comparator, lookups and removals go through it
+    assert_eq!(ones.get(&2).copied(), Some("a"));
+    assert_eq!(tens.get(&29).copied(), Some("c"));
+    let (rest, removed) = ones.remove(&41);
+    assert_eq!(removed, Some("b"));
+    assert_eq!(rest.clone().put(3, "d").keys().collect::<Vec<_>>(), vec![&12, &3]);
+    assert_eq!(ones.union(&rest).len(), 3);
+    assert_eq!(ones.floor(&9), Some((&23, &"c")));
+}
+
+#[test]
+fn map_diff() {
+    let old: Map<i32, i32> = (0..1000).map(|k| (k, k)).collect();
+    let tree = old.tree.delete(Entry::new(10, 0));
+    let changed = Map::from(tree).put(7, 70).put(500, -1).put(2000, 1);
+
+    assert_eq!(old.diff(&changed).collect::<Vec<_>>(), vec![
+        DiffItem::Changed(7, 7, 70),
+        DiffItem::Removed(10, 10),
+        DiffItem::Changed(500, 500, -1),
+        DiffItem::Added(2000, 1),
+    ]);
+    assert_eq!(changed.diff(&old).next(), Some(DiffItem::Changed(7, 70, 7)));
+    assert_eq!(old.diff(&old).count(), 0);
+    assert_eq!(Map::new().diff(&old).count(), 1000);
+}
+
+#[test]
+fn map_merge3() {
+    let base: Map<i32, i32> = (0..100).map(|k| (k, k)).collect();
+    let ours = base.insert(1, 10).0.put(2, 20).remove(&3).0.put(200, 1).put(5, 50);
+    let theirs = base.insert(1, 11).0.put(2, 20).remove(&4).0.put(300, 1).remove(&5).0;
+
+    let (merged, conflicts) = Map::merge3(&base, &ours, &theirs, |conflict| conflict.ours);
+    assert_eq!(conflicts, vec![
+        Conflict { key: 1, base: Some(1), ours: Some(10), theirs: Some(11) },
+        Conflict { key: 5, base: Some(5), ours: Some(50), theirs: None },
+    ]);
+    assert_eq!((merged[&1], merged[&2], merged[&5], merged[&200], merged[&300]), (10, 20, 50, 1, 1));
+    assert!(!merged.contains_key(&3) && !merged.contains_key(&4));
+    assert_eq!(merged.len(), 100);
+
+    //The resolver decides, `None` drops the key
+    let (merged, _) = Map::merge3(&base, &ours, &theirs, |conflict| conflict.theirs);
+    assert_eq!((merged[&1], merged.get(&5)), (11, None));
+
+    //Nothing changed on one side gives the other
+    let (merged, conflicts) = Map::merge3(&base, &base, &theirs, |_| None);
+    assert!(conflicts.is_empty());
+    assert_eq!(merged.diff(&theirs).count(), 0);
+    assert_eq!(Map::merge3(&base, &ours, &base, |_| None).0.diff(&ours).count(), 0);
+}
+
+#[test]
+fn map_insert_replaces() {
+    let map: Map<&str, i32> = vec![("a", 1), ("b", 2), ("a", 3)].into_iter().collect();
+    assert_eq!(map.get("a").copied(), Some(3));
+
+    let (updated, old) = map.insert("b", 20);
+    assert_eq!(old, Some(2));
+    assert_eq!(updated.get("b").copied(), Some(20));
+    assert_eq!(map.get("b").copied(), Some(2));
+
+    let (grown, old) = updated.insert("c", 30);
+    assert_eq!(old, None);
+    assert_eq!(grown.tree.length(), 3);
+    assert_eq!(grown.put("c", 31).get("c").copied(), Some(31));
+}
+
+#[test]
+fn map_api() {
+    let map: Map<String, i32> = vec![("b", 2), ("a", 1), ("c", 3)]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect();
+    assert_eq!(map.len(), 3);
+    assert!(!map.is_empty());
+    assert_eq!(map.get("b"), Some(&2));
+    assert_eq!(map.get("z"), None);
+    assert_eq!(map.get_key_value("a"), Some((&"a".to_string(), &1)));
+    assert!(map.contains_key("c"));
+    assert_eq!(map["c"], 3);
+
+    assert_eq!(map.keys().cloned().collect::<Vec<_>>(), vec!["a", "b", "c"]);
+    assert_eq!(map.values().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+    assert_eq!(map.iter().map(|(k, v)| format!("{}{}", k, v)).collect::<Vec<_>>(), vec!["a1", "b2", "c3"]);
+
+    let (removed, old) = map.remove("b");
+    assert_eq!(old, Some(2));
+    assert_eq!(removed.len(), 2);
+    assert!(!removed.contains_key("b"));
+    assert!(map.contains_key("b"));
+    assert_eq!(removed.remove("b").1, None);
+    assert!(Map::<i32, i32>::new().is_empty());
+
+    let entry = Entry::new(1, 'x');
+    assert_eq!((entry.key(), entry.value()), (&1, &'x'));
+}
+
+#[test]
+#[should_panic]
+fn map_index_missing_key() {
+    let map: Map<i32, i32> = Map::new();
+    let _ = map[&1];
+}
+
+#[test]
+fn map_update() {
+    let words = ["a", "b", "a", "c", "a", "b"];
+    let counts = words.iter().fold(Map::<&str, i32>::new(), |counts, word| {
+        counts.update(word, |count| Some(count.map_or(1, |c| c + 1)))
+    });
+    assert_eq!(counts.iter().collect::<Vec<_>>(), vec![(&"a", &3), (&"b", &2), (&"c", &1)]);
+
+    let removed = counts.update("b", |_| None);
+    assert!(!removed.contains_key("b"));
+    assert_eq!(counts["b"], 2);
+    assert_eq!(counts.update("z", |_| None).len(), 3);
+
+    assert_eq!(counts.update_with("a", |c| c * 10)["a"], 30);
+    assert!(!counts.update_with("z", |c| c * 10).contains_key("z"));
+
+    let merged = counts.insert_with("a", 5, |old, new| old + new).insert_with("d", 5, |old, new| old + new);
+    assert_eq!((merged["a"], merged["d"]), (8, 5));
+}
+
+#[test]
+fn map_entry() {
+    let map: Map<&str, i32> = Map::new();
+    let map = map.entry("a").or_insert(1);
+    let map = map.entry("a").and_modify(|v| *v += 10).or_insert(1);
+    let map = map.entry("b").and_modify(|v| *v += 10).or_insert(1);
+    let map = map.entry("c").or_default();
+    let map = map.entry("a").and_modify(|v| *v *= 2).and_modify(|v| *v += 1).or_insert_with(|| 0);
+    assert_eq!(map.iter().collect::<Vec<_>>(), vec![(&"a", &23), (&"b", &1), (&"c", &0)]);
+    assert_eq!(map.entry("z").key(), &"z");
+}
+
+#[test]
+fn map_merge_with() {
+    let a: Map<i32, i32> = (0..10).map(|k| (k, k)).collect();
+    let b: Map<i32, i32> = (5..15).map(|k| (k, 100)).collect();
+
+    let union = a.union_with(&b, |_, x, y| x + y);
+    assert_eq!(union.len(), 15);
+    assert_eq!((union[&2], union[&7], union[&12]), (2, 107, 100));
+
+    let intersection = a.intersection_with(&b, |k, x, y| k + x * y);
+    assert_eq!(intersection.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+               (5..10).map(|k| (k, k + k * 100)).collect::<Vec<_>>());
+
+    let merged = a.merge_with(&b,
+                              |k, v| if k % 2 == 0 { Some(v * 10) } else { None },
+                              |_, _| None,
+                              |_, x, y| if x % 2 == 0 { None } else { Some(x - y) });
+    assert_eq!(merged.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>(),
+               vec![(0, 0), (2, 20), (4, 40), (5, -95), (7, -93), (9, -91)]);
+
+    assert_eq!(a.union_with(&Map::new(), |_, x, _| *x).len(), 10);
+    assert!(a.intersection_with(&Map::new(), |_, x, _| *x).is_empty());
+}
+
+#[test]
+fn map_filter_and_map_values() {
+    let map: Map<i32, i32, Sum<i32>> = (0..50).map(|k| (k, k)).collect();
+
+    let squares = map.map_values(|_, v| v * v);
+    assert_eq!(squares.summary(), Sum((0..50).map(|k| k * k).sum()));
+    assert_eq!(squares[&7], 49);
+    assert_eq!(map[&7], 7);
+
+    let labels: Map<i32, String> = Map::<i32, i32>::from_sorted_iter((0..3).map(|k| (k, k))).unwrap()
+        .map_values(|k, v| format!("{}:{}", k, v));
+    assert_eq!(labels.values().cloned().collect::<Vec<_>>(), vec!["0:0", "1:1", "2:2"]);
+
+    let evens = map.filter(|k, _| k % 2 == 0);
+    assert_eq!(evens.keys().cloned().collect::<Vec<_>>(), (0..25).map(|k| k * 2).collect::<Vec<_>>());
+
+    let (small, large) = map.partition(|_, v| *v < 10);
+    assert_eq!((small.len(), large.len()), (10, 40));
+    assert_eq!(small.summary(), Sum(45));
+
+    let halves = map.filter_map(|_, v| if v % 2 == 0 { Some(v / 2) } else { None });
+    assert_eq!(halves.len(), 25);
+    assert_eq!(halves.summary(), Sum((0..25).sum()));
+    assert_eq!(halves.iter().last(), Some((&48, &24)));
+}
+
+#[test]
+fn map_operators() {
+    let a = map!{1 => "a", 2 => "a", 3 => "a"};
+    let b = map!{2 => "b", 3 => "b", 4 => "b"};
+
+    assert_eq!((&a | &b).iter().collect::<Vec<_>>(), vec![(&1, &"a"), (&2, &"a"), (&3, &"a"), (&4, &"b")]);
+    assert_eq!((&b & &a).iter().collect::<Vec<_>>(), vec![(&2, &"b"), (&3, &"b")]);
+    assert_eq!((&a - &b).keys().collect::<Vec<_>>(), vec![&1]);
+    assert_eq!((&a ^ &b).keys().collect::<Vec<_>>(), vec![&1, &4]);
 }
@@ -0,0 +1,133 @@
+//! Paths into maps of maps.
+//!
+//! A `Map` whose values can themselves hold a map of the same kind is a tree, and a slice of keys
+//! names a value somewhere in it. Updates along a path copy only the maps on the path, every
+//! other entry is shared with the original.
+//!
+//! Every level uses the same key type and the default measure and comparator, and values say
+//! whether they hold a map through `Associative`. `Nested` is the ready-made value type for such
+//! trees; a `Map<K, Map<K2, V>>` with a different key type at each level has no path methods.
+
+use Rc;
+
+use map::Map;
+
+/// A value that may hold a map of values of its own type.
+pub trait Associative<K: Ord + Clone>: Clone + Sized {
+    fn as_map(&self) -> Option<&Map<K, Self>>;
+    fn from_map(map: Map<K, Self>) -> Self;
+}
+
+/// A tree of maps with `V` at the leaves, e.g. a configuration.
+#[derive(Clone)]
+pub enum Nested<K: Ord + Clone, V: Clone> {
+    Leaf(V),
+    //Behind an `Rc` because tree nodes hold their entries inline
+    Map(Rc<Map<K, Nested<K, V>>>),
+}
+
+impl<K: Ord + Clone, V: Clone> Nested<K, V> {
+    pub fn leaf(&self) -> Option<&V> {
+        match self {
+            Nested::Leaf(val) => Some(val),
+            Nested::Map(_) => None,
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> Associative<K> for Nested<K, V> {
+    fn as_map(&self) -> Option<&Map<K, Self>> {
+        match self {
+            Nested::Leaf(_) => None,
+            Nested::Map(map) => Some(map),
+        }
+    }
+
+    fn from_map(map: Map<K, Self>) -> Self {
+        Nested::Map(Rc::new(map))
+    }
+}
+
+//An empty path names no value, so lookups along it find nothing and updates leave the map as it is
+impl<K: Ord + Clone, V: Associative<K>> Map<K, V> {
+    /// The value at the end of `path`, if every key before the last leads to a map.
+    pub fn get_in(&self, path: &[K]) -> Option<&V> {
+        let (last, spine) = path.split_last()?;
+        let mut map = self;
+        for key in spine {
+            map = map.get(key)?.as_map()?;
+        }
+        map.get(last)
+    }
+
+    /// Replaces the value at the end of `path` with `f` applied to it, `None` removing it. Missing maps
+    /// along the path are created only when `f` returns a value. A value on the path that is not a map
+    /// is left as it is and `f` is not called.
+    pub fn update_in<F>(&self, path: &[K], f: F) -> Self where F: FnOnce(Option<&V>) -> Option<V> {
+        let (key, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return self.clone(),
+        };
+        if rest.is_empty() {
+            return self.update(key.clone(), f);
+        }
+        self.update(key.clone(), |child| match child {
+            None => {
+                let created = Map::new().update_in(rest, f);
+                if created.is_empty() { None } else { Some(V::from_map(created)) }
+            }
+            Some(child) => Some(match child.as_map() {
+                Some(map) => V::from_map(map.update_in(rest, f)),
+                None => child.clone(),
+            }),
+        })
+    }
+
+    /// Sets the value at the end of `path`, creating maps along it as `update_in` does.
+    pub fn assoc_in(&self, path: &[K], val: V) -> Self {
+        self.update_in(path, |_| Some(val))
+    }
+
+    /// Removes the value at the end of `path`. Maps left empty stay in place, a missing path changes nothing.
+    pub fn dissoc_in(&self, path: &[K]) -> Self {
+        if self.get_in(path).is_none() {
+            return self.clone();
+        }
+        self.update_in(path, |_| None)
+    }
+}
+
+#[test]
+fn nested_paths() {
+    let leaf = |val: i32| Nested::Leaf(val);
+    let config: Map<&str, Nested<&str, i32>> = Map::new()
+        .assoc_in(&["server", "port"], leaf(80))
+        .assoc_in(&["server", "tls", "port"], leaf(443))
+        .assoc_in(&["workers"], leaf(4));
+
+    assert_eq!(config.get_in(&["server", "tls", "port"]).and_then(Nested::leaf), Some(&443));
+    assert_eq!(config.get_in(&["workers"]).and_then(Nested::leaf), Some(&4));
+    assert!(config.get_in(&["server", "tls"]).and_then(Nested::as_map).is_some());
+    assert!(config.get_in(&["workers", "port"]).is_none());
+    assert!(config.get_in(&[]).is_none());
+
+    let bumped = config.update_in(&["server", "port"], |port| port.and_then(Nested::leaf).map(|port| leaf(port + 8000)));
+    assert_eq!(bumped.get_in(&["server", "port"]).and_then(Nested::leaf), Some(&8080));
+    assert_eq!(bumped.get_in(&["server", "tls", "port"]).and_then(Nested::leaf), Some(&443));
+    assert_eq!(config.get_in(&["server", "port"]).and_then(Nested::leaf), Some(&80));
+
+    let removed = bumped.dissoc_in(&["server", "tls", "port"]);
+    assert!(removed.get_in(&["server", "tls", "port"]).is_none());
+    assert!(removed.get_in(&["server", "tls"]).and_then(Nested::as_map).unwrap().is_empty());
+    assert!(removed.dissoc_in(&["server", "missing", "port"]).get_in(&["server", "missing"]).is_none());
+
+    //A leaf on the way ends the path, nothing is written past it
+    let unchanged = config.assoc_in(&["workers", "max"], leaf(8));
+    assert_eq!(unchanged.get_in(&["workers"]).and_then(Nested::leaf), Some(&4));
+    assert!(unchanged.get_in(&["workers", "max"]).is_none());
+
+    //Removing along a missing path creates nothing
+    let empty: Map<&str, Nested<&str, i32>> = Map::new();
+    assert!(empty.update_in(&["a", "b"], |_| None).is_empty());
+    assert!(config.update_in(&["server", "missing", "port"], |_| None).get_in(&["server", "missing"]).is_none());
+}
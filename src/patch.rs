@@ -0,0 +1,222 @@
+//! Patches: the edits taking one version of a `Map` to another, as a value that can be stored,
+//! sent and applied elsewhere.
+//!
+//! Every operation carries the values it replaces, so applying a patch checks that the target
+//! still holds them and a patch can be inverted without the map it was made from.
+
+use std::error::Error;
+use std::fmt;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use map::{DiffItem, Entry, Map};
+use tree::comparator::Comparator;
+use tree::measure::Measure;
+
+/// One edit of a `Patch`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Op<K, V> {
+    Insert(K, V),
+    //The key with the value it had
+    Remove(K, V),
+    //The key with its old and new value
+    Replace(K, V, V),
+}
+
+impl<K, V> Op<K, V> {
+    pub fn key(&self) -> &K {
+        match self {
+            Op::Insert(key, _) | Op::Remove(key, _) | Op::Replace(key, _, _) => key,
+        }
+    }
+
+    /// The operation undoing this one.
+    pub fn invert(self) -> Self {
+        match self {
+            Op::Insert(key, val) => Op::Remove(key, val),
+            Op::Remove(key, val) => Op::Insert(key, val),
+            Op::Replace(key, old, new) => Op::Replace(key, new, old),
+        }
+    }
+}
+
+impl<K, V> From<DiffItem<K, V>> for Op<K, V> {
+    fn from(change: DiffItem<K, V>) -> Self {
+        match change {
+            DiffItem::Added(key, val) => Op::Insert(key, val),
+            DiffItem::Removed(key, val) => Op::Remove(key, val),
+            DiffItem::Changed(key, old, new) => Op::Replace(key, old, new),
+        }
+    }
+}
+
+/// Why a patch could not be built, applied or composed, with the key of the failing operation.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum PatchError<K> {
+    //An insert found the key present
+    Present(K),
+    //A remove or replace found the key absent
+    Missing(K),
+    //A remove or replace found the key holding another value
+    Mismatch(K),
+    //Two operations of one patch have the same key
+    Duplicate(K),
+}
+
+impl<K: fmt::Debug> fmt::Display for PatchError<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::Present(ref key) => write!(f, "patch inserts {:?} which is already present", key),
+            PatchError::Missing(ref key) => write!(f, "patch changes {:?} which is missing", key),
+            PatchError::Mismatch(ref key) => write!(f, "patch expects another value for {:?}", key),
+            PatchError::Duplicate(ref key) => write!(f, "patch has more than one operation on {:?}", key),
+        }
+    }
+}
+
+impl<K: fmt::Debug> Error for PatchError<K> {}
+
+/// A list of operations, at most one per key.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Patch<K, V> {
+    ops: Vec<Op<K, V>>,
+}
+
+impl<K, V> Patch<K, V> {
+    pub fn new() -> Self {
+        Patch { ops: vec![] }
+    }
+
+    /// A patch of `ops` in the given order, failing if two of them have the same key.
+    pub fn from_ops<I: IntoIterator<Item=Op<K, V>>>(ops: I) -> Result<Self, PatchError<K>> where K: Ord + Clone {
+        let ops: Vec<Op<K, V>> = ops.into_iter().collect();
+        let mut keys: Vec<&K> = ops.iter().map(Op::key).collect();
+        keys.sort();
+        if let Some(pair) = keys.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(PatchError::Duplicate(pair[0].clone()));
+        }
+        Ok(Patch { ops })
+    }
+
+    /// The patch taking `old` to `new`, in key order. Costs the same as `Map::diff`.
+    pub fn between<M, C>(old: &Map<K, V, M, C>, new: &Map<K, V, M, C>) -> Self
+        where K: Clone, V: Clone + PartialEq, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone
+    {
+        Patch { ops: old.diff(new).map(Op::from).collect() }
+    }
+
+    pub fn ops(&self) -> &[Op<K, V>] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// The patch undoing this one.
+    pub fn invert(self) -> Self {
+        Patch { ops: self.ops.into_iter().map(Op::invert).collect() }
+    }
+
+    /// A single patch with the effect of applying `self` and then `later`. Operations on the same key
+    /// are squashed and dropped when they cancel out, the result is in key order. Fails where `later`
+    /// expects something `self` leaves differently.
+    pub fn compose(&self, later: &Self) -> Result<Self, PatchError<K>> where K: Ord + Clone, V: Clone + PartialEq {
+        let mut ops: Map<K, Op<K, V>> = self.ops.iter().map(|op| (op.key().clone(), op.clone())).collect();
+        for op in &later.ops {
+            let squashed = match ops.get(op.key()) {
+                None => Some(op.clone()),
+                Some(earlier) => Patch::squash(earlier, op)?,
+            };
+            ops = ops.update(op.key().clone(), |_| squashed);
+        }
+        Ok(Patch { ops: ops.values().cloned().collect() })
+    }
+
+    //One operation with the effect of `earlier` and then `later` on the same key, `None` if there is no effect
+    fn squash(earlier: &Op<K, V>, later: &Op<K, V>) -> Result<Option<Op<K, V>>, PatchError<K>> where K: Clone, V: Clone + PartialEq {
+        let key = earlier.key().clone();
+        //What `earlier` leaves behind has to be what `later` expects, `None` stands for the key being absent
+        let (old, left, expected, new) = match (earlier, later) {
+            (Op::Insert(_, left), Op::Remove(_, expected)) => (None, left, expected, None),
+            (Op::Insert(_, left), Op::Replace(_, expected, new)) => (None, left, expected, Some(new)),
+            (Op::Replace(_, old, left), Op::Remove(_, expected)) => (Some(old), left, expected, None),
+            (Op::Replace(_, old, left), Op::Replace(_, expected, new)) => (Some(old), left, expected, Some(new)),
+            (Op::Remove(_, old), Op::Insert(_, new)) => (Some(old), old, old, Some(new)),
+            (Op::Remove(..), _) => return Err(PatchError::Missing(key)),
+            (_, Op::Insert(..)) => return Err(PatchError::Present(key)),
+        };
+        if left != expected {
+            return Err(PatchError::Mismatch(key));
+        }
+        Ok(match (old, new) {
+            (None, None) => None,
+            (None, Some(new)) => Some(Op::Insert(key, new.clone())),
+            (Some(old), None) => Some(Op::Remove(key, old.clone())),
+            (Some(old), Some(new)) if old == new => None,
+            (Some(old), Some(new)) => Some(Op::Replace(key, old.clone(), new.clone())),
+        })
+    }
+}
+
+impl<K, V> Default for Patch<K, V> {
+    fn default() -> Self {
+        Patch::new()
+    }
+}
+
+impl<K, V> IntoIterator for Patch<K, V> {
+    type Item = Op<K, V>;
+    type IntoIter = ::std::vec::IntoIter<Op<K, V>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+#[test]
+fn patch_apply_and_invert() {
+    let old: Map<&str, i32> = map!{"a" => 1, "b" => 2, "c" => 3};
+    let new = old.remove("a").0.put("b", 20).put("d", 4);
+    let patch = Patch::between(&old, &new);
+    assert_eq!(patch.ops(), &[Op::Remove("a", 1), Op::Replace("b", 2, 20), Op::Insert("d", 4)]);
+
+    let applied = old.apply_patch(&patch).unwrap();
+    assert!(applied.iter().eq(new.iter()));
+    let undone = applied.apply_patch(&patch.clone().invert()).unwrap();
+    assert!(undone.iter().eq(old.iter()));
+
+    //Preconditions are checked against the target
+    assert_eq!(new.apply_patch(&patch).err(), Some(PatchError::Missing("a")));
+    assert_eq!(old.insert("b", 5).0.apply_patch(&patch).err(), Some(PatchError::Mismatch("b")));
+    assert_eq!(old.insert("d", 0).0.apply_patch(&patch).err(), Some(PatchError::Present("d")));
+}
+
+#[test]
+fn patch_compose() {
+    let v0: Map<&str, i32> = map!{"a" => 1, "b" => 2, "c" => 3};
+    let v1 = v0.insert("b", 20).0.remove("c").0.put("x", 9);
+    let v2 = v1.insert("b", 2).0.put("c", 30).remove("x").0.put("a", 10);
+    let first = Patch::between(&v0, &v1);
+    let second = Patch::between(&v1, &v2);
+
+    let squashed = first.compose(&second).unwrap();
+    assert_eq!(squashed.ops(), &[Op::Replace("a", 1, 10), Op::Replace("c", 3, 30)]);
+    assert!(v0.apply_patch(&squashed).unwrap().iter().eq(v2.iter()));
+    assert!(first.compose(&first.clone().invert()).unwrap().is_empty());
+
+    assert_eq!(first.compose(&first).err(), Some(PatchError::Mismatch("b")));
+    assert_eq!(second.compose(&first).err(), Some(PatchError::Mismatch("c")));
+    let remove = Patch::from_ops(vec![Op::Remove("c", 3)]).unwrap();
+    assert_eq!(remove.compose(&remove).err(), Some(PatchError::Missing("c")));
+
+    //A patch holds one operation per key, so composing never has to pick between two
+    let twice = Patch::from_ops(vec![Op::Remove("c", 3), Op::Insert("a", 1), Op::Insert("c", 4)]);
+    assert_eq!(twice.err(), Some(PatchError::Duplicate("c")));
+}
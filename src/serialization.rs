@@ -0,0 +1,256 @@
+//! Serde support, enabled by the `serde` feature.
+//!
+//! `List` and `Set` serialize as sequences, `BinaryTree` as its elements in order and `Map` as a map.
+//! A `Patch` is a sequence of its operations, each an enum variant holding the key and values.
+//! Sorted input is rebuilt into a balanced tree in O(n), anything else falls back to inserting
+//! element by element with later duplicates replacing earlier ones. Wrap the target type in
+//! `Unique` to reject duplicates instead.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{Deserialize, Deserializer, Error, MapAccess, SeqAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+use list::List;
+use map::{Entry, Map};
+use patch::{Op, Patch};
+use set::Set;
+use tree::binary_tree::{BinaryTree, SortedError};
+use tree::comparator::Comparator;
+use tree::measure::Measure;
+
+/// Deserializes a `BinaryTree`, `Set` or `Map` and fails on the first duplicate element or key.
+#[derive(Clone, Debug)]
+pub struct Unique<T>(pub T);
+
+//Builds a tree from elements in input order, reporting the index of a duplicate when `unique` is set
+fn build_tree<E, M, C>(elems: Vec<E>, unique: bool) -> Result<BinaryTree<E, M, C>, SortedError>
+    where E: Clone, M: Measure<E>, C: Comparator<E> + Default
+{
+    let cmp = C::default();
+    let order = |a: &E, b: &E| cmp.compare(a, b);
+    if elems.windows(2).all(|pair| order(&pair[0], &pair[1]) == Ordering::Less) {
+        return Ok(BinaryTree::from_sorted_iter_unchecked(elems));
+    }
+    let mut tree = BinaryTree::empty();
+    for (index, elem) in elems.into_iter().enumerate() {
+        let (next, old) = tree.insert_returning_old_by(elem, &order);
+        if unique && old.is_some() {
+            return Err(SortedError::Duplicate(index));
+        }
+        tree = next;
+    }
+    Ok(tree)
+}
+
+struct SeqVisitor<E>(PhantomData<E>);
+
+impl<'de, E: Deserialize<'de>> Visitor<'de> for SeqVisitor<E> {
+    type Value = Vec<E>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a sequence")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut elems = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(elem) = seq.next_element()? {
+            elems.push(elem);
+        }
+        Ok(elems)
+    }
+}
+
+struct MapVisitor<K, V>(PhantomData<(K, V)>);
+
+impl<'de, K: Deserialize<'de>, V: Deserialize<'de>> Visitor<'de> for MapVisitor<K, V> {
+    type Value = Vec<Entry<K, V>>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a map")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+        let mut entries = Vec::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((key, val)) = map.next_entry()? {
+            entries.push(Entry::new(key, val));
+        }
+        Ok(entries)
+    }
+}
+
+fn deserialize_seq<'de, D: Deserializer<'de>, E: Deserialize<'de>>(deserializer: D) -> Result<Vec<E>, D::Error> {
+    deserializer.deserialize_seq(SeqVisitor(PhantomData))
+}
+
+fn deserialize_map<'de, D, K, V>(deserializer: D) -> Result<Vec<Entry<K, V>>, D::Error>
+    where D: Deserializer<'de>, K: Deserialize<'de>, V: Deserialize<'de>
+{
+    deserializer.deserialize_map(MapVisitor(PhantomData))
+}
+
+impl<E: Serialize + Clone> Serialize for List<E> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self)
+    }
+}
+
+impl<'de, E: Deserialize<'de> + Clone> Deserialize<'de> for List<E> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        //Consing from the back keeps the first element at the head
+        let elems: Vec<E> = deserialize_seq(deserializer)?;
+        Ok(elems.into_iter().rev().fold(List::empty(), |list, elem| List::cons(elem, list)))
+    }
+}
+
+impl<E: Serialize + Clone, M: Measure<E>, C: Comparator<E>> Serialize for BinaryTree<E, M, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de, E, M, C> Deserialize<'de> for BinaryTree<E, M, C>
+    where E: Deserialize<'de> + Clone, M: Measure<E>, C: Comparator<E> + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        build_tree(deserialize_seq(deserializer)?, false).map_err(D::Error::custom)
+    }
+}
+
+impl<'de, E, M, C> Deserialize<'de> for Unique<BinaryTree<E, M, C>>
+    where E: Deserialize<'de> + Clone, M: Measure<E>, C: Comparator<E> + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        build_tree(deserialize_seq(deserializer)?, true).map(Unique).map_err(D::Error::custom)
+    }
+}
+
+impl<E: Serialize + Clone, C: Comparator<E> + Clone> Serialize for Set<E, C> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de, E, C> Deserialize<'de> for Set<E, C> where E: Deserialize<'de> + Clone, C: Comparator<E> + Clone + Default {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tree: BinaryTree<E, (), C> = build_tree(deserialize_seq(deserializer)?, false).map_err(D::Error::custom)?;
+        Ok(tree.into())
+    }
+}
+
+impl<'de, E, C> Deserialize<'de> for Unique<Set<E, C>> where E: Deserialize<'de> + Clone, C: Comparator<E> + Clone + Default {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tree: BinaryTree<E, (), C> = build_tree(deserialize_seq(deserializer)?, true).map_err(D::Error::custom)?;
+        Ok(Unique(tree.into()))
+    }
+}
+
+impl<K, V, M, C> Serialize for Map<K, V, M, C>
+    where K: Serialize + Clone, V: Serialize + Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de, K, V, M, C> Deserialize<'de> for Map<K, V, M, C>
+    where K: Deserialize<'de> + Clone, V: Deserialize<'de> + Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tree = build_tree(deserialize_map(deserializer)?, false).map_err(D::Error::custom)?;
+        Ok(tree.into())
+    }
+}
+
+impl<'de, K, V, M, C> Deserialize<'de> for Unique<Map<K, V, M, C>>
+    where K: Deserialize<'de> + Clone, V: Deserialize<'de> + Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone + Default
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let tree = build_tree(deserialize_map(deserializer)?, true).map_err(D::Error::custom)?;
+        Ok(Unique(tree.into()))
+    }
+}
+
+impl<K: Serialize, V: Serialize> Serialize for Patch<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.ops())
+    }
+}
+
+impl<'de, K: Deserialize<'de> + Ord + Clone + fmt::Debug, V: Deserialize<'de>> Deserialize<'de> for Patch<K, V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Patch::from_ops(deserialize_seq::<D, Op<K, V>>(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::Unique;
+    use list::List;
+    use map::Map;
+    use patch::{Op, Patch};
+    use set::Set;
+    use tree::binary_tree::BinaryTree;
+    use tree::measure::Sum;
+
+    #[test]
+    fn serde_round_trip() {
+        let list: List<i32> = list![1, 2, 3];
+        let json = serde_json::to_string(&list).unwrap();
+        assert_eq!(json, "[1,2,3]");
+        assert_eq!(serde_json::from_str::<List<i32>>(&json).unwrap(), list);
+
+        let tree: BinaryTree<i32> = (0..10).rev().collect();
+        let json = serde_json::to_string(&tree).unwrap();
+        assert_eq!(json, "[0,1,2,3,4,5,6,7,8,9]");
+        //Rebuilt from sorted input, so the contents match but the shape need not
+        assert!(serde_json::from_str::<BinaryTree<i32>>(&json).unwrap().iter().eq(tree.iter()));
+
+        let set = set![3, 1, 2];
+        let json = serde_json::to_string(&set).unwrap();
+        assert_eq!(json, "[1,2,3]");
+        assert!(serde_json::from_str::<Set<i32>>(&json).unwrap().iter().eq(set.iter()));
+
+        let map = map!{"b".to_string() => 2, "a".to_string() => 1};
+        let json = serde_json::to_string(&map).unwrap();
+        assert_eq!(json, r#"{"a":1,"b":2}"#);
+        let back: Map<String, i32, Sum<i32>> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.summary(), Sum(3));
+        assert_eq!(back["b"], 2);
+    }
+
+    #[test]
+    fn serde_unsorted_and_duplicate_input() {
+        let tree: BinaryTree<i32> = serde_json::from_str("[5,1,3,1]").unwrap();
+        assert_eq!(tree.iter().cloned().collect::<Vec<_>>(), vec![1, 3, 5]);
+
+        let map: Map<String, i32> = serde_json::from_str(r#"{"a":1,"b":2,"a":3}"#).unwrap();
+        assert_eq!((map.len(), map["a"]), (2, 3));
+
+        assert!(serde_json::from_str::<Unique<Map<String, i32>>>(r#"{"a":1,"b":2,"a":3}"#).is_err());
+        assert!(serde_json::from_str::<Unique<Set<i32>>>("[1,2,2]").is_err());
+        assert!(serde_json::from_str::<Unique<BinaryTree<i32>>>("[3,1,2]").is_ok());
+        assert_eq!(serde_json::from_str::<Unique<Set<i32>>>("[1,2,3]").unwrap().0.len(), 3);
+    }
+
+    #[test]
+    fn serde_patch() {
+        let old = map!{"a".to_string() => 1, "b".to_string() => 2};
+        let new = old.remove("a").0.put("b".to_string(), 20).put("c".to_string(), 3);
+        let patch = Patch::between(&old, &new);
+        let json = serde_json::to_string(&patch).unwrap();
+        assert_eq!(json, r#"[{"Remove":["a",1]},{"Replace":["b",2,20]},{"Insert":["c",3]}]"#);
+
+        let received: Patch<String, i32> = serde_json::from_str(&json).unwrap();
+        assert_eq!(received, patch);
+        assert!(old.apply_patch(&received).unwrap().iter().eq(new.iter()));
+
+        assert!(serde_json::from_str::<Op<String, i32>>(r#"{"Replace":["b",2]}"#).is_err());
+        assert!(serde_json::from_str::<Op<String, i32>>(r#"{"Rename":["b",2]}"#).is_err());
+        assert!(serde_json::from_str::<Patch<String, i32>>(r#"[{"Remove":["a",1]},{"Insert":["a",2]}]"#).is_err());
+    }
+}
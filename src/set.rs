@@ -1,13 +1,399 @@
-use tree::binary_tree::{ BinaryTree, Iter };
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::iter::{FromIterator, IntoIterator};
+use std::ops::{BitAnd, BitOr, BitXor, Sub};
 
-pub struct Set<E: Eq + Ord + Clone> {
-    tree: BinaryTree<E>
+use tree::binary_tree::{BinaryTree, Diff, InOrder, Iter, SortedError};
+use tree::comparator::{Comparator, OrdComparator};
+
+/// Builds a `Set` from a list of elements, duplicates are kept once.
+#[macro_export]
+macro_rules! set {
+    [$($elem:expr),* $(,)*] => {
+        <$crate::set::Set<_> as ::std::iter::FromIterator<_>>::from_iter(vec![$($elem),*])
+    };
+}
+
+/// One difference between two sets, see `Set::diff`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum DiffItem<E> {
+    Added(E),
+    Removed(E),
+}
+
+/// A persistent sorted set. Elements are ordered by the comparator `C`, which defaults to `Ord`.
+/// Like `Map`, every set derived from this one keeps a clone of its comparator.
+pub struct Set<E: Clone, C = OrdComparator> {
+    tree: BinaryTree<E, (), C>,
+    cmp: C,
 }
 
-impl<E: Eq + Ord + Clone> Set<E> {
-    pub fn new(&self) -> Self {
+impl<E: Clone, C: Comparator<E> + Clone> Set<E, C> {
+    pub fn new() -> Self where C: Default {
+        Set::with_comparator(C::default())
+    }
+
+    /// An empty set whose elements are ordered by `cmp`.
+    pub fn with_comparator(cmp: C) -> Self {
         Set {
-            tree: BinaryTree::empty()
+            tree: BinaryTree::empty(),
+            cmp,
         }
     }
+
+    pub fn comparator(&self) -> &C {
+        &self.cmp
+    }
+
+    //A set derived from this one, ordered by the same comparator
+    fn with_tree(&self, tree: BinaryTree<E, (), C>) -> Self {
+        Set { tree, cmp: self.cmp.clone() }
+    }
+
+    #[inline]
+    fn order(&self) -> impl Fn(&E, &E) -> Ordering + '_ {
+        move |a: &E, b: &E| self.cmp.compare(a, b)
+    }
+
+    #[inline]
+    fn by_key<Q: ?Sized>(&self) -> impl Fn(&E, &Q) -> Ordering + '_ where E: Borrow<Q>, C: Comparator<Q> {
+        move |elem: &E, key: &Q| self.cmp.compare(elem.borrow(), key)
+    }
+
+    pub fn insert(&self, elem: E) -> Self {
+        self.with_tree(self.tree.insert_by(elem, &self.order()))
+    }
+
+    pub fn remove<Q: ?Sized>(&self, elem: &Q) -> Self where E: Borrow<Q>, C: Comparator<Q> {
+        self.with_tree(self.tree.update_by(elem, &self.by_key(), |_| None))
+    }
+
+    pub fn contains<Q: ?Sized>(&self, elem: &Q) -> bool where E: Borrow<Q>, C: Comparator<Q> {
+        self.tree.find_by(elem, &self.by_key()).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.length()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// The elements in order.
+    pub fn iter(&self) -> InOrder<'_, E, (), C> {
+        self.tree.iter()
+    }
+
+    pub fn from_sorted_iter<I: IntoIterator<Item=E>>(iterator: I) -> Result<Self, SortedError> where C: Default {
+        let set = Self::new();
+        let tree = BinaryTree::from_sorted_iter_by(iterator, &set.order())?;
+        Ok(set.with_tree(tree))
+    }
+
+    pub fn from_sorted_iter_unchecked<I: IntoIterator<Item=E>>(iterator: I) -> Self where C: Default {
+        Self::new().with_tree(BinaryTree::from_sorted_iter_unchecked(iterator))
+    }
+
+    pub fn first(&self) -> Option<&E> {
+        self.tree.first()
+    }
+
+    pub fn last(&self) -> Option<&E> {
+        self.tree.last()
+    }
+
+    pub fn pop_first(&self) -> Option<(E, Self)> {
+        self.tree.pop_first().map(|(first, tree)| (first, self.with_tree(tree)))
+    }
+
+    pub fn pop_last(&self) -> Option<(E, Self)> {
+        self.tree.pop_last().map(|(last, tree)| (last, self.with_tree(tree)))
+    }
+
+    /// The largest element less than or equal to `elem`.
+    pub fn floor<Q: ?Sized>(&self, elem: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> {
+        self.tree.floor_by(elem, &self.by_key())
+    }
+
+    /// The smallest element greater than or equal to `elem`.
+    pub fn ceiling<Q: ?Sized>(&self, elem: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> {
+        self.tree.ceiling_by(elem, &self.by_key())
+    }
+
+    /// The largest element strictly less than `elem`.
+    pub fn predecessor<Q: ?Sized>(&self, elem: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> {
+        self.tree.predecessor_by(elem, &self.by_key())
+    }
+
+    /// The smallest element strictly greater than `elem`.
+    pub fn successor<Q: ?Sized>(&self, elem: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> {
+        self.tree.successor_by(elem, &self.by_key())
+    }
+
+    /// The elements added or removed going from `self` to `other`, in order.
+    /// Subtrees the two sets share are skipped.
+    pub fn diff(&self, other: &Self) -> impl Iterator<Item=DiffItem<E>> {
+        //Elements equal under the comparator are the same element, so nothing is ever changed
+        let cmp = self.cmp.clone();
+        self.tree.diff_by(&other.tree, move |a: &E, b: &E| cmp.compare(a, b), |_: &E, _: &E| true)
+            .filter_map(|change| match change {
+                Diff::Added(elem) => Some(DiffItem::Added(elem)),
+                Diff::Removed(elem) => Some(DiffItem::Removed(elem)),
+                Diff::Changed(..) => None,
+            })
+    }
+
+    /// The elements that satisfy `pred`, in O(n).
+    pub fn filter<P>(&self, pred: P) -> Self where P: Fn(&E) -> bool {
+        self.with_tree(self.tree.filter(pred))
+    }
+
+    /// The elements that satisfy `pred` and the elements that don't, in O(n).
+    pub fn partition<P>(&self, pred: P) -> (Self, Self) where P: Fn(&E) -> bool {
+        let (yes, no) = self.tree.partition(pred);
+        (self.with_tree(yes), self.with_tree(no))
+    }
+
+    /// Keeps only the elements that satisfy `pred`. Other versions of the set are not affected.
+    pub fn retain<P>(&mut self, pred: P) where P: Fn(&E) -> bool {
+        self.tree = self.tree.filter(pred);
+    }
+
+    /// Whether every element of `self` is in `other`. Subtrees the sets share are not visited.
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.tree.is_subset_by(&other.tree, &self.order())
+    }
+
+    pub fn is_superset(&self, other: &Self) -> bool {
+        other.tree.is_subset_by(&self.tree, &self.order())
+    }
+
+    pub fn is_disjoint(&self, other: &Self) -> bool {
+        self.tree.is_disjoint_by(&other.tree, &self.order())
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.union_by(&other.tree, &self.order()))
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.intersection_by(&other.tree, &self.order()))
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.difference_by(&other.tree, &self.order()))
+    }
+
+    pub fn symmetric_difference(&self, other: &Self) -> Self {
+        self.with_tree(self.tree.symmetric_difference_by(&other.tree, &self.order()))
+    }
+}
+
+impl<E: Clone, C: Comparator<E> + Clone> BitOr<&Set<E, C>> for &Set<E, C> {
+    type Output = Set<E, C>;
+
+    fn bitor(self, other: &Set<E, C>) -> Set<E, C> {
+        self.union(other)
+    }
+}
+
+impl<E: Clone, C: Comparator<E> + Clone> BitAnd<&Set<E, C>> for &Set<E, C> {
+    type Output = Set<E, C>;
+
+    fn bitand(self, other: &Set<E, C>) -> Set<E, C> {
+        self.intersection(other)
+    }
+}
+
+impl<E: Clone, C: Comparator<E> + Clone> Sub<&Set<E, C>> for &Set<E, C> {
+    type Output = Set<E, C>;
+
+    fn sub(self, other: &Set<E, C>) -> Set<E, C> {
+        self.difference(other)
+    }
+}
+
+impl<E: Clone, C: Comparator<E> + Clone> BitXor<&Set<E, C>> for &Set<E, C> {
+    type Output = Set<E, C>;
+
+    fn bitxor(self, other: &Set<E, C>) -> Set<E, C> {
+        self.symmetric_difference(other)
+    }
+}
+
+impl<E: Clone, C: Comparator<E> + Clone + Default> From<BinaryTree<E, (), C>> for Set<E, C> {
+    fn from(tree: BinaryTree<E, (), C>) -> Self {
+        Self::new().with_tree(tree)
+    }
+}
+
+impl<E: Clone, C: Clone> Clone for Set<E, C> {
+    fn clone(&self) -> Self {
+        Set { tree: self.tree.clone(), cmp: self.cmp.clone() }
+    }
+}
+
+impl<E: Clone, C: Comparator<E> + Clone + Default> Default for Set<E, C> {
+    fn default() -> Self {
+        Set::new()
+    }
+}
+
+impl<E: Clone, C: Comparator<E>> IntoIterator for Set<E, C> {
+    type Item = E;
+    type IntoIter = Iter<E, (), C>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.tree.into_iter()
+    }
+}
+
+impl<'a, E: Clone, C: Comparator<E>> IntoIterator for &'a Set<E, C> {
+    type Item = &'a E;
+    type IntoIter = InOrder<'a, E, (), C>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.tree.iter()
+    }
+}
+
+impl<E: Clone, C: Comparator<E> + Clone + Default> FromIterator<E> for Set<E, C> {
+    fn from_iter<I: IntoIterator<Item=E>>(iterator: I) -> Self {
+        iterator.into_iter().fold(Set::new(), |set, elem| set.insert(elem))
+    }
+}
+
+#[test]
+fn set_macro() {
+    let empty: Set<i32> = set![];
+    assert!(empty.tree.is_empty());
+
+    let set = set![3, 1, 2, 1];
+    assert_eq!(set.tree.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+    let set = set![
+        "b",
+        "a",
+    ];
+    assert_eq!((set.first(), set.last()), (Some(&"a"), Some(&"b")));
+}
+
+#[test]
+fn set_algebra() {
+    let a: Set<i32> = Set::from_sorted_iter(0..10).unwrap();
+    let b: Set<i32> = Set::from_sorted_iter(5..15).unwrap();
+
+    assert_eq!(a.union(&b).tree.length(), 15);
+    assert_eq!(a.intersection(&b).tree.length(), 5);
+    assert_eq!(a.intersection(&b).tree.get(&7), Some(7));
+    assert_eq!(a.difference(&b).tree.get(&7), None);
+    assert_eq!(a.difference(&b).tree.get(&3), Some(3));
+    assert_eq!(a.symmetric_difference(&b).tree.length(), 10);
+    assert_eq!(a.symmetric_difference(&b).tree.get(&12), Some(12));
+}
+
+#[test]
+fn set_neighbors() {
+    let set: Set<i32> = Set::from_sorted_iter(vec![1, 4, 9, 16]).unwrap();
+    assert_eq!(set.floor(&5), Some(&4));
+    assert_eq!(set.ceiling(&5), Some(&9));
+    assert_eq!(set.predecessor(&1), None);
+    assert_eq!(set.successor(&9), Some(&16));
+    let names: Set<String> = vec!["b".to_string(), "d".to_string()].into_iter().collect();
+    assert_eq!(names.ceiling("c"), Some(&"d".to_string()));
+    assert_eq!(names.predecessor("b"), None);
+
+    let (last, rest) = set.pop_last().unwrap();
+    assert_eq!(last, 16);
+    assert_eq!(rest.last(), Some(&9));
+    assert_eq!(set.last(), Some(&16));
+}
+
+#[test]
+fn set_comparator() {
+    use tree::comparator::ReverseOrder;
+
+    let a: Set<i32, ReverseOrder> = Set::from_sorted_iter((0..10).rev()).unwrap();
+    let b: Set<i32, ReverseOrder> = Set::from_sorted_iter((5..15).rev()).unwrap();
+    assert_eq!(a.first(), Some(&9));
+    assert_eq!(a.floor(&20), None);
+    assert_eq!(a.ceiling(&20), Some(&9));
+
+    let union = a.union(&b);
+    assert_eq!(union.tree.length(), 15);
+    assert_eq!(union.first(), Some(&14));
+    assert_eq!(union.last(), Some(&0));
+    assert_eq!(a.difference(&b).first(), Some(&4));
+}
+
+#[test]
+fn set_diff() {
+    let old: Set<i32> = Set::from_sorted_iter(0..100).unwrap();
+    let new = Set::from(old.tree.delete(40).insert(150));
+
+    assert_eq!(old.diff(&new).collect::<Vec<_>>(), vec![DiffItem::Removed(40), DiffItem::Added(150)]);
+    assert_eq!(new.diff(&old).collect::<Vec<_>>(), vec![DiffItem::Added(40), DiffItem::Removed(150)]);
+    assert_eq!(old.diff(&old).count(), 0);
+}
+
+#[test]
+fn set_filter_partition_retain() {
+    let set: Set<i32> = Set::from_sorted_iter(0..30).unwrap();
+    assert_eq!(set.filter(|x| x % 3 == 0).tree.length(), 10);
+
+    let (small, large) = set.partition(|&x| x < 12);
+    assert_eq!((small.last(), large.first()), (Some(&11), Some(&12)));
+
+    let mut odd = Set::from(set.tree.clone());
+    odd.retain(|x| x % 2 == 1);
+    assert_eq!(odd.first(), Some(&1));
+    assert_eq!(odd.tree.length(), 15);
+    assert_eq!(set.tree.length(), 30);
+}
+
+#[test]
+fn set_api() {
+    let set: Set<String> = vec!["pear", "apple", "fig", "apple"].into_iter().map(String::from).collect();
+    assert_eq!(set.len(), 3);
+    assert!(!set.is_empty());
+    assert!(set.contains("fig"));
+    assert!(!set.contains("kiwi"));
+    assert_eq!(set.iter().cloned().collect::<Vec<_>>(), vec!["apple", "fig", "pear"]);
+    assert_eq!((set.first().unwrap().as_str(), set.last().unwrap().as_str()), ("apple", "pear"));
+
+    let grown = set.insert("kiwi".to_string());
+    assert!(grown.contains("kiwi"));
+    assert!(!set.contains("kiwi"));
+
+    let shrunk = grown.remove("apple");
+    assert_eq!(shrunk.len(), 3);
+    assert!(!shrunk.contains("apple"));
+    assert_eq!(shrunk.remove("apple").len(), 3);
+
+    let mut seen = vec![];
+    for fruit in &shrunk {
+        seen.push(fruit.clone());
+    }
+    assert_eq!(seen, shrunk.clone().into_iter().collect::<Vec<_>>());
+    assert!(Set::<i32>::new().is_empty());
+    assert!(Set::<i32>::default().remove(&1).is_empty());
+}
+
+#[test]
+fn set_operators() {
+    let granted = set!["read", "write", "admin"];
+    let revoked = set!["admin", "delete"];
+
+    assert_eq!((&granted - &revoked).iter().collect::<Vec<_>>(), vec![&"read", &"write"]);
+    assert_eq!((&granted | &revoked).len(), 4);
+    assert_eq!((&granted & &revoked).iter().collect::<Vec<_>>(), vec![&"admin"]);
+    assert_eq!((&granted ^ &revoked).iter().collect::<Vec<_>>(), vec![&"delete", &"read", &"write"]);
+
+    assert!((&granted - &revoked).is_subset(&granted));
+    assert!(granted.is_superset(&(&granted & &revoked)));
+    assert!((&granted - &revoked).is_disjoint(&revoked));
+    assert!(!granted.is_disjoint(&revoked));
 }
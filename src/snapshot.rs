@@ -0,0 +1,646 @@
+//! A binary format for many versions of the persistent collections at once.
+//!
+//! Every distinct node reachable from the saved roots is written once, identified by its address,
+//! so versions that share subtrees or tails share them on disk too. Loading restores the sharing.
+//!
+//! Layout, all integers little endian:
+//! `"IMSN"`, version `u16`, node count `u32`, the nodes children first, root count `u32`,
+//! the roots, and a CRC-32 of everything before it. A node is a kind byte, a color byte, two child
+//! references and a length prefixed value. Reference 0 is the empty tree or list, reference i is
+//! the i-th node, and nodes may only refer to nodes written before them. A root is a kind byte,
+//! a node reference and a `u32` tag, the CRC-32 of the element and comparator type names.
+//!
+//! Loading checks the red-black invariants of every tree node, and decoding a tree checks its order
+//! under the comparator it is loaded with, so a damaged or crafted file can't produce a broken tree.
+//! Tags are built from `std::any::type_name`, so a snapshot should be read by a build of the same
+//! types it was written by.
+
+use std::any::{type_name, Any};
+use std::cmp::Ordering;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+use Rc;
+
+use list::List;
+use map::{Entry, KeyComparator, Map};
+use tree::binary_tree::{BinaryTree, Color};
+use tree::comparator::Comparator;
+use tree::measure::Measure;
+
+const MAGIC: &[u8; 4] = b"IMSN";
+pub const VERSION: u16 = 2;
+
+const TREE_NODE: u8 = 0;
+const LIST_NODE: u8 = 1;
+
+const TREE_ROOT: u8 = 0;
+const LIST_ROOT: u8 = 1;
+const MAP_ROOT: u8 = 2;
+
+//A red-black tree of at most `u32::MAX` nodes is never deeper than this
+const MAX_DEPTH: u8 = 64;
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    //The input ended in the middle of a record
+    Truncated,
+    //A record that can't have been written by SnapshotWriter, with the index of the node or root
+    Corrupt(usize),
+    //The root does not hold the kind of collection or element type it was loaded as
+    TypeMismatch(usize),
+    NoSuchRoot(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::BadMagic => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Corrupt(index) => write!(f, "corrupt snapshot record {}", index),
+            SnapshotError::TypeMismatch(root) => write!(f, "root {} has a different type", root),
+            SnapshotError::NoSuchRoot(root) => write!(f, "no root {} in snapshot", root),
+        }
+    }
+}
+
+impl Error for SnapshotError {}
+
+/// Element types that can be stored in a snapshot.
+pub trait SnapshotValue: Sized {
+    fn write(&self, out: &mut Vec<u8>);
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError>;
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8], SnapshotError> {
+    if input.len() < len {
+        return Err(SnapshotError::Truncated);
+    }
+    let (head, tail) = input.split_at(len);
+    *input = tail;
+    Ok(head)
+}
+
+macro_rules! snapshot_int {
+    ($($int:ty),*) => {$(
+        impl SnapshotValue for $int {
+            fn write(&self, out: &mut Vec<u8>) {
+                out.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+                let mut bytes = [0; ::std::mem::size_of::<$int>()];
+                let len = bytes.len();
+                bytes.copy_from_slice(take(input, len)?);
+                Ok(<$int>::from_le_bytes(bytes))
+            }
+        }
+    )*};
+}
+
+snapshot_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl SnapshotValue for bool {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.push(*self as u8);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        match u8::read(input)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(SnapshotError::Corrupt(0)),
+        }
+    }
+}
+
+impl SnapshotValue for String {
+    fn write(&self, out: &mut Vec<u8>) {
+        (self.len() as u32).write(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        let len = u32::read(input)? as usize;
+        String::from_utf8(take(input, len)?.to_vec()).map_err(|_| SnapshotError::Corrupt(0))
+    }
+}
+
+impl<A: SnapshotValue, B: SnapshotValue> SnapshotValue for (A, B) {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.0.write(out);
+        self.1.write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        Ok((A::read(input)?, B::read(input)?))
+    }
+}
+
+impl<K: SnapshotValue, V: SnapshotValue> SnapshotValue for Entry<K, V> {
+    fn write(&self, out: &mut Vec<u8>) {
+        self.key().write(out);
+        self.value().write(out);
+    }
+
+    fn read(input: &mut &[u8]) -> Result<Self, SnapshotError> {
+        Ok(Entry::new(K::read(input)?, V::read(input)?))
+    }
+}
+
+//CRC-32 as used by zip and png
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn color_byte(color: Color) -> u8 {
+    match color {
+        Color::Red => 0,
+        Color::Black => 1,
+        _ => unreachable!("only red and black nodes are left once an operation finishes"),
+    }
+}
+
+fn byte_color(byte: u8) -> Option<Color> {
+    match byte {
+        0 => Some(Color::Red),
+        1 => Some(Color::Black),
+        _ => None,
+    }
+}
+
+//Names the element and comparator types of a root so it can't be loaded as other types
+fn tag<E: ?Sized, C: ?Sized>() -> u32 {
+    crc32(format!("{}/{}", type_name::<E>(), type_name::<C>()).as_bytes())
+}
+
+/// Collects roots and writes them as one snapshot.
+///
+/// The roots stay borrowed until `finish`, which keeps every node alive so no address is reused
+/// by a different node while the writer identifies nodes by address.
+pub struct SnapshotWriter<'a> {
+    nodes: Vec<u8>,
+    count: u32,
+    ids: HashMap<usize, u32>,
+    roots: Vec<(u8, u32, u32)>,
+    borrowed: PhantomData<&'a ()>,
+}
+
+impl<'a> SnapshotWriter<'a> {
+    pub fn new() -> Self {
+        SnapshotWriter {
+            nodes: vec![],
+            count: 0,
+            ids: HashMap::new(),
+            roots: vec![],
+            borrowed: PhantomData,
+        }
+    }
+
+    /// Adds a root and returns its index in the snapshot.
+    pub fn add_tree<E, M, C>(&mut self, tree: &'a BinaryTree<E, M, C>) -> usize where E: SnapshotValue {
+        let node = self.tree_node(tree);
+        self.add_root(TREE_ROOT, node, tag::<E, C>())
+    }
+
+    pub fn add_map<K, V, M, C>(&mut self, map: &'a Map<K, V, M, C>) -> usize
+        where K: SnapshotValue + Clone, V: SnapshotValue + Clone, M: Measure<Entry<K, V>>, C: Comparator<K> + Clone
+    {
+        let node = self.tree_node(map.as_tree());
+        self.add_root(MAP_ROOT, node, tag::<Entry<K, V>, KeyComparator<C>>())
+    }
+
+    pub fn add_list<E: SnapshotValue>(&mut self, list: &'a List<E>) -> usize {
+        //Written iteratively, lists can be much longer than trees are deep
+        let mut pending = vec![];
+        let mut cell = list;
+        let mut tail_id = 0;
+        while let List::Cons(_, ref tail) = *cell {
+            if let Some(&id) = self.ids.get(&(cell as *const List<E> as usize)) {
+                tail_id = id;
+                break;
+            }
+            pending.push(cell);
+            cell = tail;
+        }
+        for cell in pending.into_iter().rev() {
+            if let List::Cons(ref value, _) = *cell {
+                tail_id = self.write_node(cell as *const List<E> as usize, LIST_NODE, 0, 0, tail_id, value);
+            }
+        }
+        self.add_root(LIST_ROOT, tail_id, tag::<E, ()>())
+    }
+
+    /// The encoded snapshot.
+    pub fn finish(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.nodes.len() + 16 + self.roots.len() * 9);
+        out.extend_from_slice(MAGIC);
+        VERSION.write(&mut out);
+        self.count.write(&mut out);
+        out.extend_from_slice(&self.nodes);
+        (self.roots.len() as u32).write(&mut out);
+        for &(kind, node, tag) in &self.roots {
+            kind.write(&mut out);
+            node.write(&mut out);
+            tag.write(&mut out);
+        }
+        let checksum = crc32(&out);
+        checksum.write(&mut out);
+        out
+    }
+
+    fn add_root(&mut self, kind: u8, node: u32, tag: u32) -> usize {
+        self.roots.push((kind, node, tag));
+        self.roots.len() - 1
+    }
+
+    fn tree_node<E: SnapshotValue, M, C>(&mut self, tree: &BinaryTree<E, M, C>) -> u32 {
+        match *tree {
+            BinaryTree::Empty => 0,
+            BinaryTree::Node(color, ref value, ref left, ref right, _, _, _) => {
+                let address = tree as *const BinaryTree<E, M, C> as usize;
+                if let Some(&id) = self.ids.get(&address) {
+                    return id;
+                }
+                let left = self.tree_node(left);
+                let right = self.tree_node(right);
+                self.write_node(address, TREE_NODE, color_byte(color), left, right, value)
+            }
+        }
+    }
+
+    fn write_node<E: SnapshotValue>(&mut self, address: usize, kind: u8, color: u8, first: u32, second: u32, value: &E) -> u32 {
+        kind.write(&mut self.nodes);
+        color.write(&mut self.nodes);
+        first.write(&mut self.nodes);
+        second.write(&mut self.nodes);
+        //Length prefixed so the reader can find record boundaries without knowing the element type
+        let start = self.nodes.len();
+        0u32.write(&mut self.nodes);
+        value.write(&mut self.nodes);
+        let len = (self.nodes.len() - start - 4) as u32;
+        self.nodes[start..start + 4].copy_from_slice(&len.to_le_bytes());
+
+        self.count += 1;
+        self.ids.insert(address, self.count);
+        self.count
+    }
+}
+
+impl<'a> Default for SnapshotWriter<'a> {
+    fn default() -> Self {
+        SnapshotWriter::new()
+    }
+}
+
+struct Record {
+    kind: u8,
+    color: u8,
+    first: u32,
+    second: u32,
+    value: (usize, usize),
+}
+
+/// A loaded snapshot. Roots are decoded on request, and nodes shared between roots that are
+/// loaded as the same type are decoded once and shared again.
+pub struct Snapshot {
+    bytes: Vec<u8>,
+    records: Vec<Record>,
+    roots: Vec<(u8, u32, u32)>,
+    //Each entry is the `Rc` of a decoded node, boxed so nodes of any type fit in one cache
+    decoded: RefCell<HashMap<u32, Box<dyn Any>>>,
+}
+
+impl Snapshot {
+    /// Checks the header, checksum and structure of `bytes`. Values are decoded later.
+    pub fn load(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        if bytes.len() < 4 || &bytes[..4] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if bytes.len() < 14 {
+            return Err(SnapshotError::Truncated);
+        }
+        let (body, mut checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(body) != u32::read(&mut checksum)? {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+
+        let mut input = &body[4..];
+        let version = u16::read(&mut input)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let count = u32::read(&mut input)? as usize;
+        let mut records: Vec<Record> = Vec::with_capacity(count.min(body.len() / 14));
+        //The depth and black height of each tree node
+        let mut shapes: Vec<(u8, u8)> = Vec::with_capacity(records.capacity());
+        for index in 0..count {
+            let kind = u8::read(&mut input)?;
+            let color = u8::read(&mut input)?;
+            let first = u32::read(&mut input)?;
+            let second = u32::read(&mut input)?;
+            let len = u32::read(&mut input)? as usize;
+            let start = body.len() - input.len();
+            take(&mut input, len)?;
+            //Children must come first, which rules out cycles
+            let valid = match kind {
+                TREE_NODE => byte_color(color).is_some() && first as usize <= index && second as usize <= index,
+                LIST_NODE => first == 0 && second as usize <= index,
+                _ => false,
+            };
+            if !valid {
+                return Err(SnapshotError::Corrupt(index));
+            }
+            let shape = if kind == TREE_NODE {
+                Snapshot::tree_shape(&records, &shapes, color, first, second).ok_or(SnapshotError::Corrupt(index))?
+            } else {
+                (0, 0)
+            };
+            records.push(Record { kind, color, first, second, value: (start, start + len) });
+            shapes.push(shape);
+        }
+
+        let root_count = u32::read(&mut input)? as usize;
+        let mut roots = Vec::with_capacity(root_count.min(input.len() / 5));
+        for index in 0..root_count {
+            let kind = u8::read(&mut input)?;
+            let node = u32::read(&mut input)?;
+            let tag = u32::read(&mut input)?;
+            if kind > MAP_ROOT || node as usize > count {
+                return Err(SnapshotError::Corrupt(index));
+            }
+            roots.push((kind, node, tag));
+        }
+        if !input.is_empty() {
+            return Err(SnapshotError::Corrupt(root_count));
+        }
+
+        Ok(Snapshot { bytes: body.to_vec(), records, roots, decoded: RefCell::new(HashMap::new()) })
+    }
+
+    //Checks a tree node against its children, which are already checked, and returns its depth and black height
+    fn tree_shape(records: &[Record], shapes: &[(u8, u8)], color: u8, first: u32, second: u32) -> Option<(u8, u8)> {
+        let child = |node: u32| match node {
+            0 => Some((None, (0, 0))),
+            node => {
+                let record = &records[node as usize - 1];
+                if record.kind != TREE_NODE { None } else { Some((byte_color(record.color), shapes[node as usize - 1])) }
+            }
+        };
+        let (left_color, (left_depth, left_black)) = child(first)?;
+        let (right_color, (right_depth, right_black)) = child(second)?;
+        let color = byte_color(color)?;
+        let red_child = left_color == Some(Color::Red) || right_color == Some(Color::Red);
+        if left_black != right_black || (color == Color::Red && red_child) {
+            return None;
+        }
+        let depth = left_depth.max(right_depth) + 1;
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        Some((depth, left_black + (color == Color::Black) as u8))
+    }
+
+    pub fn roots(&self) -> usize {
+        self.roots.len()
+    }
+
+    pub fn tree<E, M, C>(&self, root: usize) -> Result<BinaryTree<E, M, C>, SnapshotError>
+        where E: SnapshotValue + Clone + 'static, M: Measure<E> + 'static, C: Comparator<E> + Default + 'static
+    {
+        let node = self.root(root, TREE_ROOT, tag::<E, C>())?;
+        self.tree_node(node, root, &C::default()).map(|tree| (*tree).clone())
+    }
+
+    pub fn map<K, V, M, C>(&self, root: usize) -> Result<Map<K, V, M, C>, SnapshotError>
+        where K: SnapshotValue + Clone + 'static, V: SnapshotValue + Clone + 'static,
+              M: Measure<Entry<K, V>> + 'static, C: Comparator<K> + Clone + Default + 'static
+    {
+        let node = self.root(root, MAP_ROOT, tag::<Entry<K, V>, KeyComparator<C>>())?;
+        let tree: Rc<BinaryTree<Entry<K, V>, M, KeyComparator<C>>> = self.tree_node(node, root, &KeyComparator(C::default()))?;
+        Ok(Map::from((*tree).clone()))
+    }
+
+    pub fn list<E: SnapshotValue + Clone + 'static>(&self, root: usize) -> Result<List<E>, SnapshotError> {
+        let node = self.root(root, LIST_ROOT, tag::<E, ()>())?;
+        //Walk down to the first cell that is already decoded, then cons back up
+        let mut pending = vec![];
+        let mut cell = node;
+        let mut list: Rc<List<E>> = Rc::new(List::Empty);
+        while cell != 0 {
+            if let Some(decoded) = self.cached(cell, root)? {
+                list = decoded;
+                break;
+            }
+            pending.push(cell);
+            cell = self.records[cell as usize - 1].second;
+        }
+        for cell in pending.into_iter().rev() {
+            let record = &self.records[cell as usize - 1];
+            if record.kind != LIST_NODE {
+                return Err(SnapshotError::TypeMismatch(root));
+            }
+            let value = self.value(record, cell)?;
+            list = Rc::new(List::Cons(value, list));
+            self.decoded.borrow_mut().insert(cell, Box::new(list.clone()));
+        }
+        Ok((*list).clone())
+    }
+
+    fn root(&self, root: usize, kind: u8, tag: u32) -> Result<u32, SnapshotError> {
+        match self.roots.get(root) {
+            None => Err(SnapshotError::NoSuchRoot(root)),
+            Some(&(found, _, found_tag)) if found != kind || found_tag != tag => Err(SnapshotError::TypeMismatch(root)),
+            Some(&(_, node, _)) => Ok(node),
+        }
+    }
+
+    fn cached<T: 'static>(&self, node: u32, root: usize) -> Result<Option<Rc<T>>, SnapshotError> {
+        match self.decoded.borrow().get(&node) {
+            None => Ok(None),
+            Some(decoded) => decoded.downcast_ref::<Rc<T>>().cloned().map(Some).ok_or(SnapshotError::TypeMismatch(root)),
+        }
+    }
+
+    fn value<E: SnapshotValue>(&self, record: &Record, node: u32) -> Result<E, SnapshotError> {
+        let mut input = &self.bytes[record.value.0..record.value.1];
+        let value = E::read(&mut input).map_err(|_| SnapshotError::Corrupt(node as usize - 1))?;
+        if !input.is_empty() {
+            return Err(SnapshotError::Corrupt(node as usize - 1));
+        }
+        Ok(value)
+    }
+
+    fn tree_node<E, M, C>(&self, node: u32, root: usize, cmp: &C) -> Result<Rc<BinaryTree<E, M, C>>, SnapshotError>
+        where E: SnapshotValue + Clone + 'static, M: Measure<E> + 'static, C: Comparator<E> + 'static
+    {
+        if node == 0 {
+            return Ok(Rc::new(BinaryTree::Empty));
+        }
+        if let Some(tree) = self.cached(node, root)? {
+            return Ok(tree);
+        }
+        let record = &self.records[node as usize - 1];
+        if record.kind != TREE_NODE {
+            return Err(SnapshotError::TypeMismatch(root));
+        }
+        let left = self.tree_node(record.first, root, cmp)?;
+        let right = self.tree_node(record.second, root, cmp)?;
+        let color = byte_color(record.color).expect("colors are checked on load");
+        let value = self.value(record, node)?;
+        let ordered = left.last().is_none_or(|last| cmp.compare(last, &value) == Ordering::Less)
+            && right.first().is_none_or(|first| cmp.compare(&value, first) == Ordering::Less);
+        if !ordered {
+            return Err(SnapshotError::Corrupt(node as usize - 1));
+        }
+        let tree = Rc::new(BinaryTree::branch(color, value, left, right));
+        self.decoded.borrow_mut().insert(node, Box::new(tree.clone()));
+        Ok(tree)
+    }
+}
+
+#[test]
+fn snapshot_shares_versions() {
+    let mut versions: Vec<Map<u32, String>> = vec![(0..1000).map(|k| (k, k.to_string())).collect()];
+    for edit in 0..100 {
+        let (next, _) = versions[edit].insert(edit as u32 * 7, format!("edit {}", edit));
+        versions.push(next);
+    }
+
+    let mut writer = SnapshotWriter::new();
+    for version in &versions {
+        writer.add_map(version);
+    }
+    let bytes = writer.finish();
+
+    let mut single = SnapshotWriter::new();
+    single.add_map(&versions[0]);
+    assert!(bytes.len() < 3 * single.finish().len());
+
+    let snapshot = Snapshot::load(&bytes).unwrap();
+    assert_eq!(snapshot.roots(), 101);
+    let loaded: Vec<Map<u32, String>> = (0..101).map(|root| snapshot.map(root).unwrap()).collect();
+    for (version, loaded) in versions.iter().zip(&loaded) {
+        assert!(version.iter().eq(loaded.iter()));
+    }
+
+    //Subtrees shared in memory are shared again after loading
+    let shared = |a: &Map<u32, String>, b: &Map<u32, String>| Rc::ptr_eq(&a.as_tree().left().left(), &b.as_tree().left().left());
+    assert!(shared(&versions[99], &versions[100]));
+    assert!(shared(&loaded[99], &loaded[100]));
+}
+
+#[test]
+fn snapshot_lists_and_trees() {
+    let tail: Rc<List<i64>> = Rc::new((0..5).collect());
+    let a = List::Cons(10, tail.clone());
+    let b = List::Cons(20, tail.clone());
+    let tree: BinaryTree<(u8, bool)> = (0..20).map(|x| (x, x % 2 == 0)).collect();
+
+    let mut writer = SnapshotWriter::new();
+    writer.add_list(&a);
+    writer.add_list(&b);
+    writer.add_tree(&tree);
+    writer.add_tree(&BinaryTree::<u8>::Empty);
+    let snapshot = Snapshot::load(&writer.finish()).unwrap();
+
+    let (loaded_a, loaded_b) = (snapshot.list::<i64>(0).unwrap(), snapshot.list::<i64>(1).unwrap());
+    assert_eq!((loaded_a.clone(), loaded_b.clone()), (a, b));
+    assert!(Rc::ptr_eq(&loaded_a.tail(), &loaded_b.tail()));
+
+    let loaded: BinaryTree<(u8, bool)> = snapshot.tree(2).unwrap();
+    assert_eq!(loaded, tree);
+    assert!(snapshot.tree::<u8, (), ::tree::comparator::OrdComparator>(3).unwrap().is_empty());
+
+    assert_eq!(snapshot.map::<u8, bool, (), ::tree::comparator::OrdComparator>(2).err(), Some(SnapshotError::TypeMismatch(2)));
+    assert_eq!(snapshot.list::<i64>(9).err(), Some(SnapshotError::NoSuchRoot(9)));
+    assert_eq!(snapshot.list::<i32>(0).err(), Some(SnapshotError::TypeMismatch(0)));
+}
+
+#[test]
+fn snapshot_rejects_damage() {
+    let list: List<u8> = list![1, 2, 3];
+    let mut writer = SnapshotWriter::new();
+    writer.add_list(&list);
+    let bytes = writer.finish();
+
+    let mut flipped = bytes.clone();
+    flipped[12] ^= 1;
+    assert_eq!(Snapshot::load(&flipped).err(), Some(SnapshotError::ChecksumMismatch));
+    assert_eq!(Snapshot::load(&bytes[..bytes.len() - 1]).err(), Some(SnapshotError::ChecksumMismatch));
+    assert_eq!(Snapshot::load(b"nope").err(), Some(SnapshotError::BadMagic));
+
+    let mut future = bytes[..bytes.len() - 4].to_vec();
+    future[4] = 3;
+    let checksum = crc32(&future);
+    checksum.write(&mut future);
+    assert_eq!(Snapshot::load(&future).err(), Some(SnapshotError::UnsupportedVersion(3)));
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+//A snapshot of tree nodes given as (color, left, right, value), with one root at the last node
+#[cfg(test)]
+fn crafted(nodes: &[(u8, u32, u32, u8)], tag: u32) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    VERSION.write(&mut out);
+    (nodes.len() as u32).write(&mut out);
+    for &(color, left, right, value) in nodes {
+        out.extend_from_slice(&[TREE_NODE, color]);
+        left.write(&mut out);
+        right.write(&mut out);
+        1u32.write(&mut out);
+        value.write(&mut out);
+    }
+    1u32.write(&mut out);
+    TREE_ROOT.write(&mut out);
+    (nodes.len() as u32).write(&mut out);
+    tag.write(&mut out);
+    let checksum = crc32(&out);
+    checksum.write(&mut out);
+    out
+}
+
+#[test]
+fn snapshot_rejects_invalid_trees() {
+    use tree::comparator::{OrdComparator, ReverseOrder};
+
+    let ord = tag::<u8, OrdComparator>();
+    let valid = crafted(&[(0, 0, 0, 1), (0, 0, 0, 3), (1, 1, 2, 2)], ord);
+    let tree: BinaryTree<u8> = Snapshot::load(&valid).unwrap().tree(0).unwrap();
+    assert_eq!(tree.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+    //Written under one comparator, loaded under another
+    let reversed: BinaryTree<u8, (), ReverseOrder> = (0..10).collect();
+    let mut writer = SnapshotWriter::new();
+    writer.add_tree(&reversed);
+    let snapshot = Snapshot::load(&writer.finish()).unwrap();
+    assert_eq!(snapshot.tree::<u8, (), OrdComparator>(0).err(), Some(SnapshotError::TypeMismatch(0)));
+    assert!(snapshot.tree::<u8, (), ReverseOrder>(0).unwrap().iter().eq(reversed.iter()));
+
+    //Out of order under the tag's own comparator
+    let misordered = crafted(&[(0, 0, 0, 3), (0, 0, 0, 1), (1, 1, 2, 2)], ord);
+    assert_eq!(Snapshot::load(&misordered).unwrap().tree::<u8, (), OrdComparator>(0).err(), Some(SnapshotError::Corrupt(2)));
+
+    //Colors other than red and black, uneven black heights, red under red
+    assert_eq!(Snapshot::load(&crafted(&[(2, 0, 0, 1)], ord)).err(), Some(SnapshotError::Corrupt(0)));
+    assert_eq!(Snapshot::load(&crafted(&[(1, 0, 0, 1), (1, 1, 0, 2)], ord)).err(), Some(SnapshotError::Corrupt(1)));
+    assert_eq!(Snapshot::load(&crafted(&[(0, 0, 0, 1), (0, 1, 0, 2)], ord)).err(), Some(SnapshotError::Corrupt(1)));
+
+    //A chain too deep to be a red-black tree is refused before anything recurses over it
+    let chain: Vec<_> = (0..200u32).map(|node| (1, node, node, 0)).collect();
+    assert_eq!(Snapshot::load(&crafted(&chain, ord)).err(), Some(SnapshotError::Corrupt(MAX_DEPTH as usize)));
+}
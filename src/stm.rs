@@ -0,0 +1,325 @@
+//! Software transactional memory over `Ref`s holding immutable values.
+//!
+//! A transaction reads every ref as of the moment it started and buffers its writes. Commit checks
+//! that nothing it touched was changed by another commit and then publishes all writes at once,
+//! otherwise the transaction runs again from the start. Values are never changed in place, so
+//! rolling back is just dropping the new versions. Commits lock only the refs they write, so
+//! transactions over unrelated refs commit in parallel. As with `Atom`, refs to this crate's
+//! collections cross threads with the `sync` feature.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+//Commits are numbered from this clock, each ref remembers the commit that last wrote it
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+static NEXT_REF: AtomicUsize = AtomicUsize::new(0);
+
+struct Versioned<T> {
+    version: usize,
+    value: Arc<T>,
+}
+
+/// A mutable cell changed only by transactions. Clones refer to the same cell.
+pub struct Ref<T> {
+    id: usize,
+    cell: Arc<Mutex<Versioned<T>>>,
+}
+
+impl<T> Ref<T> {
+    pub fn new(value: T) -> Self {
+        let cell = Versioned { version: CLOCK.load(Ordering::SeqCst), value: Arc::new(value) };
+        Ref { id: NEXT_REF.fetch_add(1, Ordering::Relaxed), cell: Arc::new(Mutex::new(cell)) }
+    }
+
+    /// The latest committed value, outside of any transaction.
+    pub fn load(&self) -> Arc<T> {
+        self.current().1
+    }
+
+    fn current(&self) -> (usize, Arc<T>) {
+        let cell = self.cell.lock().unwrap();
+        (cell.version, cell.value.clone())
+    }
+}
+
+impl<T> Clone for Ref<T> {
+    fn clone(&self) -> Self {
+        Ref { id: self.id, cell: self.cell.clone() }
+    }
+}
+
+/// Another commit changed a ref the transaction depends on, the transaction has to start over.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Conflict;
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "transaction conflicts with a concurrent commit")
+    }
+}
+
+impl Error for Conflict {}
+
+//What a transaction knows about one ref, with the type erased so refs of any type fit in one log
+trait Logged {
+    fn written(&self) -> bool;
+    //Whether the ref is still as first seen, false while another commit holds it
+    fn unchanged(&self) -> bool;
+    //Holds the ref until the commit is done
+    fn lock(&self) -> Box<dyn Locked + '_>;
+    fn as_any(&mut self) -> &mut dyn Any;
+}
+
+//A written ref held by a commit
+trait Locked {
+    fn unchanged(&self) -> bool;
+    fn publish(&mut self, version: usize);
+}
+
+struct Entry<T> {
+    target: Ref<T>,
+    //The version of the ref when the transaction first touched it
+    seen: usize,
+    value: Arc<T>,
+    written: bool,
+}
+
+impl<T: 'static> Logged for Entry<T> {
+    fn written(&self) -> bool {
+        self.written
+    }
+
+    fn unchanged(&self) -> bool {
+        //Waiting here could deadlock with a commit waiting on a ref this one holds
+        self.target.cell.try_lock().is_ok_and(|cell| cell.version == self.seen)
+    }
+
+    fn lock(&self) -> Box<dyn Locked + '_> {
+        Box::new((self, self.target.cell.lock().unwrap()))
+    }
+
+    fn as_any(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+impl<'a, T> Locked for (&'a Entry<T>, MutexGuard<'a, Versioned<T>>) {
+    fn unchanged(&self) -> bool {
+        self.1.version == self.0.seen
+    }
+
+    fn publish(&mut self, version: usize) {
+        *self.1 = Versioned { version, value: self.0.value.clone() };
+    }
+}
+
+pub struct Transaction {
+    read_point: usize,
+    log: HashMap<usize, Box<dyn Logged>>,
+}
+
+impl Transaction {
+    /// The value of `target` in this transaction's snapshot, or what the transaction wrote to it.
+    pub fn read<T: 'static>(&mut self, target: &Ref<T>) -> Result<Arc<T>, Conflict> {
+        if let Some(entry) = self.entry(target) {
+            return Ok(entry.value.clone());
+        }
+        let (version, value) = target.current();
+        //Changed since the snapshot was taken, the value would not be consistent with earlier reads
+        if version > self.read_point {
+            return Err(Conflict);
+        }
+        self.log.insert(target.id, Box::new(Entry { target: target.clone(), seen: version, value: value.clone(), written: false }));
+        Ok(value)
+    }
+
+    /// Sets `target` to `value` when the transaction commits.
+    pub fn write<T: 'static>(&mut self, target: &Ref<T>, value: T) {
+        let value = Arc::new(value);
+        if let Some(entry) = self.entry(target) {
+            entry.value = value;
+            entry.written = true;
+            return;
+        }
+        let seen = target.current().0;
+        self.log.insert(target.id, Box::new(Entry { target: target.clone(), seen, value, written: true }));
+    }
+
+    /// Writes `f` applied to the value of `target` and returns the new value.
+    pub fn update<T: 'static, F: FnOnce(&T) -> T>(&mut self, target: &Ref<T>, f: F) -> Result<Arc<T>, Conflict> {
+        let value = self.read(target)?;
+        self.write(target, f(&value));
+        self.read(target)
+    }
+
+    fn entry<T: 'static>(&mut self, target: &Ref<T>) -> Option<&mut Entry<T>> {
+        self.log.get_mut(&target.id).map(|entry| entry.as_any().downcast_mut().expect("a ref id belongs to a single ref"))
+    }
+
+    fn commit(self) -> bool {
+        let mut writes: Vec<_> = self.log.iter().filter(|(_, entry)| entry.written()).collect();
+        if writes.is_empty() {
+            return true;
+        }
+        //Locking in id order keeps two commits from each waiting on a ref the other holds
+        writes.sort_by_key(|&(&id, _)| id);
+        let mut locked: Vec<_> = writes.into_iter().map(|(_, entry)| entry.lock()).collect();
+        //Readers starting before the clock moves see the new versions as too new and retry, readers
+        //starting after it wait on the locks until the new versions are published
+        let version = CLOCK.fetch_add(1, Ordering::SeqCst) + 1;
+        let reads_valid = self.log.values().filter(|entry| !entry.written()).all(|entry| entry.unchanged());
+        if !reads_valid || !locked.iter().all(|entry| entry.unchanged()) {
+            return false;
+        }
+        for entry in &mut locked {
+            entry.publish(version);
+        }
+        true
+    }
+}
+
+/// Runs `f` as a transaction until it commits and returns its result. `f` may run several times,
+/// so it should not have effects outside the transaction. Returning `Err(Conflict)` from `f`,
+/// usually by passing on a failed read with `?`, starts it over.
+pub fn transaction<R, F: FnMut(&mut Transaction) -> Result<R, Conflict>>(mut f: F) -> R {
+    loop {
+        let mut tx = Transaction { read_point: CLOCK.load(Ordering::SeqCst), log: HashMap::new() };
+        if let Ok(result) = f(&mut tx) {
+            if tx.commit() {
+                return result;
+            }
+        }
+    }
+}
+
+#[test]
+fn stm_transactions() {
+    use map::Map;
+    use set::Set;
+
+    let stock = Ref::new(map!{"apple" => 5, "pear" => 2});
+    let reserved = Ref::new(Map::<&str, i32>::new());
+    let sold_out = Ref::new(Set::<&str>::new());
+
+    let reserve = |item: &'static str, count: i32| transaction(|tx| {
+        let left = tx.read(&stock)?.get(item).cloned().unwrap_or(0);
+        if left < count {
+            return Ok(false);
+        }
+        tx.update(&stock, |stock| stock.insert(item, left - count).0)?;
+        tx.update(&reserved, |reserved| reserved.insert_with(item, count, |old, new| old + new))?;
+        if left == count {
+            tx.update(&sold_out, |sold_out| sold_out.insert(item))?;
+        }
+        Ok(true)
+    });
+    assert!(reserve("apple", 3));
+    assert!(reserve("pear", 2));
+    assert!(!reserve("apple", 3));
+    assert_eq!((stock.load()["apple"], reserved.load()["apple"]), (2, 3));
+    assert!(sold_out.load().contains("pear"));
+
+    //A commit between two reads makes the transaction start over instead of mixing versions
+    let mut attempts = 0;
+    let total = transaction(|tx| {
+        attempts += 1;
+        let apples = tx.read(&stock)?["apple"];
+        if attempts == 1 {
+            transaction(|inner| {
+                inner.update(&stock, |stock| stock.insert("apple", 1).0)?;
+                inner.update(&reserved, |reserved| reserved.insert("apple", 4).0)
+            });
+        }
+        Ok(apples + tx.read(&reserved)?["apple"])
+    });
+    assert_eq!((attempts, total), (2, 5));
+
+    //Nothing is published from a transaction that starts over
+    let mut attempts = 0;
+    transaction(|tx| {
+        attempts += 1;
+        tx.write(&stock, Map::new());
+        if attempts == 1 { Err(Conflict) } else { Ok(()) }
+    });
+    assert!(stock.load().is_empty());
+    assert_eq!(reserved.load()["apple"], 4);
+}
+
+#[test]
+fn stm_threads() {
+    use std::thread;
+
+    let accounts: Vec<Ref<u64>> = (0..4).map(|_| Ref::new(1000)).collect();
+    let threads: Vec<_> = (0..8).map(|thread| {
+        let accounts = accounts.clone();
+        thread::spawn(move || {
+            for i in 0..300 {
+                let (from, to) = (&accounts[(thread + i) % 4], &accounts[(thread + 2 * i + 1) % 4]);
+                if i % 10 == 0 {
+                    //Every snapshot sees the total that all commits preserve
+                    let total = transaction(|tx| {
+                        accounts.iter().try_fold(0, |total, account| Ok(total + *tx.read(account)?))
+                    });
+                    assert_eq!(total, 4000);
+                } else {
+                    transaction(|tx| {
+                        let amount = (*tx.read(from)?).min(7);
+                        tx.update(from, |balance| balance - amount)?;
+                        tx.update(to, |balance| balance + amount).map(|_| ())
+                    });
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(accounts.iter().map(|account| *account.load()).sum::<u64>(), 4000);
+}
+
+#[cfg(feature = "sync")]
+#[test]
+fn stm_map_threads() {
+    use std::thread;
+    use map::Map;
+
+    let items = ["apple", "pear", "plum"];
+    let stock: Map<&str, u32> = items.iter().map(|&item| (item, 100)).collect();
+    let warehouses = [Ref::new(stock), Ref::new(Map::<&str, u32>::new())];
+    let threads: Vec<_> = (0..8).map(|thread| {
+        let warehouses = warehouses.clone();
+        thread::spawn(move || {
+            for i in 0..200 {
+                let item = items[(thread + i) % items.len()];
+                let (from, to) = if (thread + i) % 3 == 0 { (&warehouses[1], &warehouses[0]) } else { (&warehouses[0], &warehouses[1]) };
+                if i % 10 == 0 {
+                    //Both maps are always seen as of the same commit
+                    let totals = transaction(|tx| {
+                        let (first, second) = (tx.read(&warehouses[0])?, tx.read(&warehouses[1])?);
+                        Ok(items.iter().map(|item| first.get(item).unwrap_or(&0) + second.get(item).unwrap_or(&0)).collect::<Vec<_>>())
+                    });
+                    assert_eq!(totals, vec![100; 3]);
+                } else {
+                    transaction(|tx| {
+                        let left = tx.read(from)?.get(item).cloned().unwrap_or(0);
+                        let moved = left.min(3);
+                        tx.update(from, |stock| stock.insert(item, left - moved).0)?;
+                        tx.update(to, |stock| stock.insert_with(item, moved, |old, new| old + new))?;
+                        Ok(())
+                    });
+                }
+            }
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let (first, second) = (warehouses[0].load(), warehouses[1].load());
+    for item in &items {
+        assert_eq!(first.get(item).unwrap_or(&0) + second.get(item).unwrap_or(&0), 100);
+    }
+}
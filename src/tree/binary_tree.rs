@@ -1,19 +1,27 @@
-use std::cmp::max;
+use std::cmp::{max, Ordering};
 use std::fmt::{Debug, Formatter, Result};
 use std::iter::{Iterator, IntoIterator, FromIterator};
 use std::ops::Deref;
-use std::rc::Rc;
+use std::ops::Bound;
+use Rc;
+use std::ptr;
 use std::borrow::Borrow;
+use std::marker::PhantomData;
+use std::error::Error;
+use std::fmt;
+use std::result;
 
 
 use list::List;
+use tree::measure::Measure;
+use tree::comparator::{Comparator, OrdComparator};
 
 #[macro_escape]
 #[macro_export]
 macro_rules! binary_tree {
     [] => {BinaryTree::Empty};
     [$($x:expr),*] => {{
-        let mut t = BinaryTree::empty();
+        let mut t: BinaryTree<_> = BinaryTree::empty();
         $(
             t = t.insert($x);
         )*
@@ -29,22 +37,158 @@ pub enum Color {
     Red
 }
 
-#[derive(Clone, Eq)]
-pub enum BinaryTree<E> {
+//Each node caches the measure of its subtree, by default the measure is () and costs nothing,
+//and the number of elements in its subtree so the length is known in O(1).
+//Elements are kept in the order given by the comparator C.
+pub enum BinaryTree<E, M = (), C = OrdComparator> {
     Empty,
-    Node(Color, E, Rc<BinaryTree<E>>, Rc<BinaryTree<E>>),
+    Node(Color, E, Rc<BinaryTree<E, M, C>>, Rc<BinaryTree<E, M, C>>, M, usize, PhantomData<C>),
 }
 
-pub struct Iter<E: Clone> {
-    stack: Rc< List< Rc< BinaryTree<E> > > >,
+pub struct Iter<E: Clone, M = (), C = OrdComparator> {
+    stack: Rc< List< Rc< BinaryTree<E, M, C> > > >,
 }
 
-impl<E> BinaryTree<E> where E: Clone + Eq + Ord {
+//Borrowing in order iterator, the stack holds the nodes whose left subtree has been visited
+pub struct InOrder<'a, E: 'a, M: 'a = (), C: 'a = OrdComparator> {
+    stack: Vec<&'a BinaryTree<E, M, C>>,
+}
+
+/// The differences between two trees, found one at a time as the iterator is advanced, see `BinaryTree::diff_by`.
+pub struct DiffIter<E: Clone, M, C, F, S> {
+    //The work left to do, the next difference comes from the top
+    pending: Vec<Pending<E, M, C>>,
+    cmp: F,
+    same: S,
+}
+
+enum Pending<E: Clone, M, C> {
+    //Two subtrees and their black heights, not compared yet
+    Pair(Rc<BinaryTree<E, M, C>>, usize, Rc<BinaryTree<E, M, C>>, usize),
+    //A difference found at a root, reported after its left subtrees
+    Found(Diff<E>),
+    //The rest of a subtree found on one side only
+    Added(Iter<E, M, C>),
+    Removed(Iter<E, M, C>),
+}
+
+/// One difference between two trees, see `BinaryTree::diff`.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub enum Diff<E> {
+    //Only in the newer tree
+    Added(E),
+    //Only in the older tree
+    Removed(E),
+    //Old and new element, equal under the comparator but otherwise different
+    Changed(E, E),
+}
+
+/// What `BinaryTree::merge_with` does with the elements found in only one of the trees.
+#[derive(Clone, Copy, Debug)]
+pub enum Only<F> {
+    //Keep them unchanged, whole subtrees are shared with the input
+    Keep,
+    Drop,
+    //Replace each element with the result of F, or remove it on None
+    With(F),
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum SortedError {
+    //Index of the first element that is less than the one before it
+    OutOfOrder(usize),
+    //Index of the first element that is equal to the one before it
+    Duplicate(usize),
+}
+
+impl fmt::Display for SortedError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            SortedError::OutOfOrder(index) => write!(f, "element {} is out of order", index),
+            SortedError::Duplicate(index) => write!(f, "element {} is a duplicate", index),
+        }
+    }
+}
+
+impl Error for SortedError {}
+
+impl<E, M, C> BinaryTree<E, M, C> where E: Clone, M: Measure<E>, C: Comparator<E> {
     #[inline]
-    pub fn node(_c: Color, val: E, left: BinaryTree<E>, right: BinaryTree<E>) -> Self {
+    pub fn node(_c: Color, val: E, left: BinaryTree<E, M, C>, right: BinaryTree<E, M, C>) -> Self {
         //assert!(left.is_empty() || left.value() < val);
         //assert!(right.is_empty() || right.value() > val);
-        BinaryTree::Node(_c, val, Rc::new(left), Rc::new(right))
+        BinaryTree::branch(_c, val, Rc::new(left), Rc::new(right))
+    }
+
+    //Every node is built through here so its measure is always in sync with its children.
+    //Crate visible so shared subtrees can be reassembled without copying them, the caller keeps the order.
+    #[inline]
+    pub(crate) fn branch(c: Color, val: E, left: Rc<Self>, right: Rc<Self>) -> Self {
+        let measure = left.summary().combine(&M::measure(&val)).combine(&right.summary());
+        let size = left.length() + 1 + right.length();
+        BinaryTree::Node(c, val, left, right, measure, size, PhantomData)
+    }
+
+    /// The combined measure of every element in the tree.
+    pub fn summary(&self) -> M {
+        match *self {
+            BinaryTree::Empty => M::identity(),
+            BinaryTree::Node(_, _, _, _, ref measure, _, _) => measure.clone(),
+        }
+    }
+
+    /// The combined measure of the elements within `lower` and `upper`, in O(log n).
+    pub fn measure_range<Q: ?Sized>(&self, lower: Bound<&Q>, upper: Bound<&Q>) -> M where E: Borrow<Q>, C: Comparator<Q> + Default {
+        self.measure_range_by(lower, upper, &Self::borrowed_order())
+    }
+
+    pub fn measure_range_by<Q: ?Sized, F>(&self, lower: Bound<&Q>, upper: Bound<&Q>, cmp: &F) -> M
+        where F: Fn(&E, &Q) -> Ordering
+    {
+        //Once both bounds have been passed the cached measure covers the whole subtree
+        if let (Bound::Unbounded, Bound::Unbounded) = (lower, upper) {
+            return self.summary();
+        }
+        match *self {
+            BinaryTree::Empty => M::identity(),
+            BinaryTree::Node(_, ref value, ref left, ref right, _, _, _) => {
+                if Self::below(value, lower, cmp) { right.measure_range_by(lower, upper, cmp) }
+                else if Self::above(value, upper, cmp) { left.measure_range_by(lower, upper, cmp) }
+                else {
+                    left.measure_range_by(lower, Bound::Unbounded, cmp)
+                        .combine(&M::measure(value))
+                        .combine(&right.measure_range_by(Bound::Unbounded, upper, cmp))
+                }
+            }
+        }
+    }
+
+    fn below<Q: ?Sized, F: Fn(&E, &Q) -> Ordering>(value: &E, lower: Bound<&Q>, cmp: &F) -> bool {
+        match lower {
+            Bound::Included(key) => cmp(value, key) == Ordering::Less,
+            Bound::Excluded(key) => cmp(value, key) != Ordering::Greater,
+            Bound::Unbounded => false,
+        }
+    }
+
+    fn above<Q: ?Sized, F: Fn(&E, &Q) -> Ordering>(value: &E, upper: Bound<&Q>, cmp: &F) -> bool {
+        match upper {
+            Bound::Included(key) => cmp(value, key) == Ordering::Greater,
+            Bound::Excluded(key) => cmp(value, key) != Ordering::Less,
+            Bound::Unbounded => false,
+        }
+    }
+
+    //The default comparator's order between elements, and of elements against borrowed keys,
+    //as closures so the plain methods can hand them to the *_by methods
+    fn order() -> impl Fn(&E, &E) -> Ordering where C: Default {
+        let cmp = C::default();
+        move |a: &E, b: &E| cmp.compare(a, b)
+    }
+
+    fn borrowed_order<Q: ?Sized>() -> impl Fn(&E, &Q) -> Ordering where E: Borrow<Q>, C: Comparator<Q> + Default {
+        let cmp = C::default();
+        move |elem: &E, key: &Q| cmp.compare(elem.borrow(), key)
     }
 
     #[inline]
@@ -52,85 +196,730 @@ impl<E> BinaryTree<E> where E: Clone + Eq + Ord {
         BinaryTree::Empty
     }
 
+    /// Builds a balanced tree in O(n) from elements in strictly ascending order.
+    pub fn from_sorted_iter<I: IntoIterator<Item=E>>(iterator: I) -> result::Result<Self, SortedError> where C: Default {
+        Self::from_sorted_iter_by(iterator, &Self::order())
+    }
+
+    /// Like `from_sorted_iter` but checks the elements against `cmp`, which must agree with the tree's order.
+    pub fn from_sorted_iter_by<I, F>(iterator: I, cmp: &F) -> result::Result<Self, SortedError>
+        where I: IntoIterator<Item=E>, F: Fn(&E, &E) -> Ordering
+    {
+        let elems: Vec<E> = iterator.into_iter().collect();
+        for (index, pair) in elems.windows(2).enumerate() {
+            match cmp(&pair[0], &pair[1]) {
+                Ordering::Greater => return Err(SortedError::OutOfOrder(index + 1)),
+                Ordering::Equal => return Err(SortedError::Duplicate(index + 1)),
+                Ordering::Less => {},
+            }
+        }
+        Ok(BinaryTree::from_sorted_vec(elems))
+    }
+
+    /// Like `from_sorted_iter` but trusts the caller that the elements are strictly ascending.
+    pub fn from_sorted_iter_unchecked<I: IntoIterator<Item=E>>(iterator: I) -> Self {
+        BinaryTree::from_sorted_vec(iterator.into_iter().collect())
+    }
+
+    fn from_sorted_vec(elems: Vec<E>) -> Self {
+        let len = elems.len();
+        //Every level above the last is full, so painting the partially filled last level red
+        //leaves all paths with the same number of black nodes
+        let mut full_levels = 0;
+        while (1 << (full_levels + 1)) - 1 <= len {
+            full_levels += 1;
+        }
+        let mut elems = elems.into_iter();
+        BinaryTree::build_sorted(&mut elems, len, 0, full_levels)
+    }
+
+    fn build_sorted<I: Iterator<Item=E>>(elems: &mut I, len: usize, depth: usize, full_levels: usize) -> Self {
+        if len == 0 {
+            return BinaryTree::Empty;
+        }
+        let left_len = len / 2;
+        let left = BinaryTree::build_sorted(elems, left_len, depth + 1, full_levels);
+        let root = elems.next().expect("build_sorted() ran out of elements");
+        let right = BinaryTree::build_sorted(elems, len - left_len - 1, depth + 1, full_levels);
+        let color = if depth < full_levels { Color::Black } else { Color::Red };
+        BinaryTree::node(color, root, left, right)
+    }
+
     #[inline]
-    pub fn unsafe_get<K>(&self, val: K) -> E where E:PartialOrd<K> {
-        match self.get(val) {
+    pub fn unsafe_get<Q: ?Sized>(&self, key: &Q) -> E where E: Borrow<Q>, C: Comparator<Q> + Default {
+        match self.get(key) {
             None => panic!("Value not found for unsafe_get call"),
             Some(value) => value,
         }
     }
 
-    pub fn get<K>(&self, val: K) -> Option<E> where E: PartialOrd<K> {
-        match self {
-            &BinaryTree::Empty => None,
-            &BinaryTree::Node(_, ref value, ref left, ref right) =>
-                if value > &val { left.get(val) }
-                else if value < &val { right.get(val) }
-                else { Some(value.clone()) }
+    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<E> where E: Borrow<Q>, C: Comparator<Q> + Default {
+        self.find_by(key, &Self::borrowed_order()).cloned()
+    }
+
+    /// Finds the element that `cmp` considers equal to `key`. `cmp` must agree with the tree's order.
+    pub fn find_by<Q: ?Sized, F>(&self, key: &Q, cmp: &F) -> Option<&E> where F: Fn(&E, &Q) -> Ordering {
+        match *self {
+            BinaryTree::Empty => None,
+            BinaryTree::Node(_, ref value, ref left, ref right, _, _, _) =>
+                match cmp(value, key) {
+                    Ordering::Greater => left.find_by(key, cmp),
+                    Ordering::Less => right.find_by(key, cmp),
+                    Ordering::Equal => Some(value),
+                }
         }
     }
 
-    pub fn insert(&self, val: E) -> Self {
-        self.ins(val).paint(Color::Black)
+    /// Inserts `val`, replacing the element that compares equal to it if there is one.
+    pub fn insert(&self, val: E) -> Self where C: Default {
+        self.insert_by(val, &Self::order())
+    }
+
+    /// Inserts `val` and returns the element it replaced, if any.
+    pub fn insert_returning_old(&self, val: E) -> (Self, Option<E>) where C: Default {
+        self.insert_returning_old_by(val, &Self::order())
+    }
+
+    /// Like `insert`, ordering by `cmp`, which must agree with the tree's order.
+    pub fn insert_by<F>(&self, val: E, cmp: &F) -> Self where F: Fn(&E, &E) -> Ordering {
+        self.insert_returning_old_by(val, cmp).0
     }
 
-    fn ins(&self, val: E) -> Self {
+    pub fn insert_returning_old_by<F>(&self, val: E, cmp: &F) -> (Self, Option<E>) where F: Fn(&E, &E) -> Ordering {
+        let mut old = None;
+        let tree = self.ins(val, cmp, &mut old).paint(Color::Black);
+        (tree, old)
+    }
+
+    fn ins<F>(&self, val: E, cmp: &F, old: &mut Option<E>) -> Self where F: Fn(&E, &E) -> Ordering {
         match self {
             &BinaryTree::Empty => BinaryTree::node(Color::Red, val, BinaryTree::Empty, BinaryTree::Empty),
-            &BinaryTree::Node(color, ref root, ref left, ref right) => {
-                if val > *root { BinaryTree::balance(color, root.clone(), left.deref().clone(), right.ins(val)) }
-                else if val < *root { BinaryTree::balance(color, root.clone(), left.ins(val), right.deref().clone()) }
-                else { self.clone() }
+            &BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                match cmp(&val, root) {
+                    Ordering::Greater => BinaryTree::balance(color, root.clone(), left.deref().clone(), right.ins(val, cmp, old)),
+                    Ordering::Less => BinaryTree::balance(color, root.clone(), left.ins(val, cmp, old), right.deref().clone()),
+                    Ordering::Equal => {
+                        *old = Some(root.clone());
+                        BinaryTree::branch(color, val, left.clone(), right.clone())
+                    }
+                }
             }
         }
     }
 
     pub fn del(&self) -> Self {
-        //Leaf case
-        if self.is_leaf() { BinaryTree::Empty }
-        //One child cases
-        else if self.left().is_empty() { self.left().deref().clone() }
-        else if self.right().is_empty() { self.right().deref().clone() }
-        //Two child case
-        else {
-            match self.inorder_successor() {
-                BinaryTree::Empty =>
-                    panic!("This should not occur, right.is_empty() check is not working"),
-
-                BinaryTree::Node(color, ref root, ref left, ref right) =>
-                    BinaryTree::Node(
-                        color,
-                        root.clone(),
-                        left.clone(),
-                        Rc::new(right.delete(root)))
+        match *self {
+            BinaryTree::Empty => BinaryTree::Empty,
+            BinaryTree::Node(color, _, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, self.black_height());
+                BinaryTree::unwrap(BinaryTree::concat_at(left.clone(), height, right.clone(), height).0)
             }
         }
     }
 
-    pub fn delete<B: Borrow<E>>(&self, val: B) -> Self {
-        match self {
-            &BinaryTree::Empty => BinaryTree::Empty,
-            &BinaryTree::Node(ref color, ref root, ref left, ref right) => {
-                if root > val.borrow() {
-                    BinaryTree::Node(
-                        color.clone(),
-                        root.clone(),
-                        Rc::new(left.delete(val)),
-                        right.clone())
+    pub fn delete<B: Borrow<E>>(&self, val: B) -> Self where C: Default {
+        self.delete_by(val, &Self::order())
+    }
+
+    /// Like `delete`, ordering by `cmp`, which must agree with the tree's order.
+    pub fn delete_by<B: Borrow<E>, F>(&self, val: B, cmp: &F) -> Self where F: Fn(&E, &E) -> Ordering {
+        let tree = Rc::new(self.clone());
+        match BinaryTree::split_at(&tree, self.black_height(), val.borrow(), cmp) {
+            (_, _, None, _, _) => self.clone(),
+            (left, left_h, Some(_), right, right_h) =>
+                BinaryTree::unwrap(BinaryTree::concat_at(left, left_h, right, right_h).0),
+        }
+    }
+
+    /// Inserts, replaces or removes the element equal to `key` in a single walk down the tree.
+    /// `f` sees the current element, if any, and returns the element to store or `None` to remove it.
+    pub fn update<Q: ?Sized, G>(&self, key: &Q, f: G) -> Self
+        where E: Borrow<Q>, C: Comparator<Q> + Default, G: FnOnce(Option<&E>) -> Option<E>
+    {
+        self.update_by(key, &Self::borrowed_order(), f)
+    }
+
+    pub fn update_by<Q: ?Sized, F, G>(&self, key: &Q, cmp: &F, f: G) -> Self
+        where F: Fn(&E, &Q) -> Ordering, G: FnOnce(Option<&E>) -> Option<E>
+    {
+        let tree = Rc::new(self.clone());
+        let (updated, _) = BinaryTree::update_at(&tree, self.black_height(), key, cmp, f);
+        if Rc::ptr_eq(&updated, &tree) { self.clone() } else { BinaryTree::unwrap(updated) }
+    }
+
+    //Rebuilds the path to `key` with joins on the way back up, which absorb the change in
+    //black height when an element is added or removed. Untouched trees are returned as is.
+    fn update_at<Q: ?Sized, F, G>(tree: &Rc<Self>, height: usize, key: &Q, cmp: &F, f: G) -> (Rc<Self>, usize)
+        where F: Fn(&E, &Q) -> Ordering, G: FnOnce(Option<&E>) -> Option<E>
+    {
+        match **tree {
+            BinaryTree::Empty => match f(None) {
+                None => (tree.clone(), height),
+                Some(elem) => (Rc::new(BinaryTree::node(Color::Black, elem, BinaryTree::Empty, BinaryTree::Empty)), 1),
+            },
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let child_h = Self::child_height(color, height);
+                match cmp(root, key) {
+                    Ordering::Greater => {
+                        let (new_left, left_h) = BinaryTree::update_at(left, child_h, key, cmp, f);
+                        if Rc::ptr_eq(&new_left, left) { return (tree.clone(), height); }
+                        BinaryTree::join_at(new_left, left_h, root.clone(), right.clone(), child_h)
+                    }
+                    Ordering::Less => {
+                        let (new_right, right_h) = BinaryTree::update_at(right, child_h, key, cmp, f);
+                        if Rc::ptr_eq(&new_right, right) { return (tree.clone(), height); }
+                        BinaryTree::join_at(left.clone(), child_h, root.clone(), new_right, right_h)
+                    }
+                    Ordering::Equal => match f(Some(root)) {
+                        None => BinaryTree::concat_at(left.clone(), child_h, right.clone(), child_h),
+                        Some(elem) => (Rc::new(BinaryTree::branch(color, elem, left.clone(), right.clone())), height),
+                    },
+                }
+            }
+        }
+    }
+
+    /// Splits the tree into the elements less than `key`, the element equal to `key` if present,
+    /// and the elements greater than `key`.
+    pub fn split<Q: ?Sized>(&self, key: &Q) -> (Self, Option<E>, Self) where E: Borrow<Q>, C: Comparator<Q> + Default {
+        self.split_by(key, &Self::borrowed_order())
+    }
+
+    pub fn split_by<Q: ?Sized, F>(&self, key: &Q, cmp: &F) -> (Self, Option<E>, Self) where F: Fn(&E, &Q) -> Ordering {
+        let tree = Rc::new(self.clone());
+        let (left, _, found, right, _) = BinaryTree::split_at(&tree, self.black_height(), key, cmp);
+        (BinaryTree::unwrap(left), found, BinaryTree::unwrap(right))
+    }
+
+    /// Joins two trees around a middle element. Every element of `left` must be less than `mid`
+    /// and every element of `right` greater than it.
+    pub fn join(left: Self, mid: E, right: Self) -> Self {
+        let (left_h, right_h) = (left.black_height(), right.black_height());
+        BinaryTree::unwrap(BinaryTree::join_at(Rc::new(left), left_h, mid, Rc::new(right), right_h).0)
+    }
+
+    /// Joins two trees where every element of `left` is less than every element of `right`.
+    pub fn concat(left: Self, right: Self) -> Self {
+        let (left_h, right_h) = (left.black_height(), right.black_height());
+        BinaryTree::unwrap(BinaryTree::concat_at(Rc::new(left), left_h, Rc::new(right), right_h).0)
+    }
+
+    /// Elements found in either tree. Where both trees hold an equal element the one from `self` is kept.
+    pub fn union(&self, other: &Self) -> Self where C: Default {
+        self.union_by(other, &Self::order())
+    }
+
+    pub fn union_by<F>(&self, other: &Self, cmp: &F) -> Self where F: Fn(&E, &E) -> Ordering {
+        self.combine(other, cmp, BinaryTree::union_at)
+    }
+
+    /// Elements of `self` that have an equal element in `other`.
+    pub fn intersection(&self, other: &Self) -> Self where C: Default {
+        self.intersection_by(other, &Self::order())
+    }
+
+    pub fn intersection_by<F>(&self, other: &Self, cmp: &F) -> Self where F: Fn(&E, &E) -> Ordering {
+        self.combine(other, cmp, BinaryTree::intersection_at)
+    }
+
+    /// Elements of `self` that have no equal element in `other`.
+    pub fn difference(&self, other: &Self) -> Self where C: Default {
+        self.difference_by(other, &Self::order())
+    }
+
+    pub fn difference_by<F>(&self, other: &Self, cmp: &F) -> Self where F: Fn(&E, &E) -> Ordering {
+        self.combine(other, cmp, BinaryTree::difference_at)
+    }
+
+    /// Elements found in exactly one of the two trees.
+    pub fn symmetric_difference(&self, other: &Self) -> Self where C: Default {
+        self.symmetric_difference_by(other, &Self::order())
+    }
+
+    pub fn symmetric_difference_by<F>(&self, other: &Self, cmp: &F) -> Self where F: Fn(&E, &E) -> Ordering {
+        self.combine(other, cmp, BinaryTree::symmetric_difference_at)
+    }
+
+    fn combine<F, O>(&self, other: &Self, cmp: &F, op: O) -> Self
+        where F: Fn(&E, &E) -> Ordering, O: Fn(&Rc<Self>, usize, &Rc<Self>, usize, &F) -> (Rc<Self>, usize)
+    {
+        let this = Rc::new(self.clone());
+        let that = if ptr::eq(self, other) { this.clone() } else { Rc::new(other.clone()) };
+        BinaryTree::unwrap(op(&this, self.black_height(), &that, other.black_height(), cmp).0)
+    }
+
+    /// Whether every element of `self` has an equal element in `other`.
+    pub fn is_subset(&self, other: &Self) -> bool where C: Default {
+        self.is_subset_by(other, &Self::order())
+    }
+
+    pub fn is_subset_by<F>(&self, other: &Self, cmp: &F) -> bool where F: Fn(&E, &E) -> Ordering {
+        self.relate(other, cmp, BinaryTree::is_subset_at)
+    }
+
+    pub fn is_superset(&self, other: &Self) -> bool where C: Default {
+        other.is_subset(self)
+    }
+
+    /// Whether no element of `self` has an equal element in `other`.
+    pub fn is_disjoint(&self, other: &Self) -> bool where C: Default {
+        self.is_disjoint_by(other, &Self::order())
+    }
+
+    pub fn is_disjoint_by<F>(&self, other: &Self, cmp: &F) -> bool where F: Fn(&E, &E) -> Ordering {
+        self.relate(other, cmp, BinaryTree::is_disjoint_at)
+    }
+
+    fn relate<F, O>(&self, other: &Self, cmp: &F, op: O) -> bool
+        where F: Fn(&E, &E) -> Ordering, O: Fn(&Rc<Self>, usize, &Rc<Self>, usize, &F) -> bool
+    {
+        let this = Rc::new(self.clone());
+        let that = if ptr::eq(self, other) { this.clone() } else { Rc::new(other.clone()) };
+        op(&this, self.black_height(), &that, other.black_height(), cmp)
+    }
+
+    //Both relations split like the set operations and give up on the first counterexample
+    fn is_subset_at<F>(this: &Rc<Self>, this_h: usize, that: &Rc<Self>, that_h: usize, cmp: &F) -> bool
+        where F: Fn(&E, &E) -> Ordering
+    {
+        if Rc::ptr_eq(this, that) {
+            return true;
+        }
+        match **this {
+            BinaryTree::Empty => true,
+            BinaryTree::Node(..) if that.is_empty() => false,
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, this_h);
+                let (less, less_h, found, greater, greater_h) = BinaryTree::split_at(that, that_h, root, cmp);
+                found.is_some()
+                    && BinaryTree::is_subset_at(left, height, &less, less_h, cmp)
+                    && BinaryTree::is_subset_at(right, height, &greater, greater_h, cmp)
+            }
+        }
+    }
+
+    fn is_disjoint_at<F>(this: &Rc<Self>, this_h: usize, that: &Rc<Self>, that_h: usize, cmp: &F) -> bool
+        where F: Fn(&E, &E) -> Ordering
+    {
+        if that.is_empty() {
+            return true;
+        }
+        if Rc::ptr_eq(this, that) {
+            return false;
+        }
+        match **this {
+            BinaryTree::Empty => true,
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, this_h);
+                let (less, less_h, found, greater, greater_h) = BinaryTree::split_at(that, that_h, root, cmp);
+                found.is_none()
+                    && BinaryTree::is_disjoint_at(left, height, &less, less_h, cmp)
+                    && BinaryTree::is_disjoint_at(right, height, &greater, greater_h, cmp)
+            }
+        }
+    }
+
+    //Each set operation exposes the root of one tree and splits the other around it,
+    //so the cost is bounded by the smaller tree. Identical subtrees are never descended into.
+    fn union_at<F>(this: &Rc<Self>, this_h: usize, that: &Rc<Self>, that_h: usize, cmp: &F) -> (Rc<Self>, usize)
+        where F: Fn(&E, &E) -> Ordering
+    {
+        if Rc::ptr_eq(this, that) || that.is_empty() {
+            return (this.clone(), this_h);
+        }
+        match **this {
+            BinaryTree::Empty => (that.clone(), that_h),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, this_h);
+                let (less, less_h, _, greater, greater_h) = BinaryTree::split_at(that, that_h, root, cmp);
+                let (new_left, left_h) = BinaryTree::union_at(left, height, &less, less_h, cmp);
+                let (new_right, right_h) = BinaryTree::union_at(right, height, &greater, greater_h, cmp);
+                if Rc::ptr_eq(&new_left, left) && Rc::ptr_eq(&new_right, right) {
+                    (this.clone(), this_h)
+                } else {
+                    BinaryTree::join_at(new_left, left_h, root.clone(), new_right, right_h)
+                }
+            }
+        }
+    }
+
+    fn intersection_at<F>(this: &Rc<Self>, this_h: usize, that: &Rc<Self>, that_h: usize, cmp: &F) -> (Rc<Self>, usize)
+        where F: Fn(&E, &E) -> Ordering
+    {
+        if Rc::ptr_eq(this, that) {
+            return (this.clone(), this_h);
+        }
+        if that.is_empty() {
+            return (that.clone(), 0);
+        }
+        match **this {
+            BinaryTree::Empty => (this.clone(), 0),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, this_h);
+                let (less, less_h, found, greater, greater_h) = BinaryTree::split_at(that, that_h, root, cmp);
+                let (new_left, left_h) = BinaryTree::intersection_at(left, height, &less, less_h, cmp);
+                let (new_right, right_h) = BinaryTree::intersection_at(right, height, &greater, greater_h, cmp);
+                if found.is_none() {
+                    BinaryTree::concat_at(new_left, left_h, new_right, right_h)
+                } else if Rc::ptr_eq(&new_left, left) && Rc::ptr_eq(&new_right, right) {
+                    (this.clone(), this_h)
+                } else {
+                    BinaryTree::join_at(new_left, left_h, root.clone(), new_right, right_h)
+                }
+            }
+        }
+    }
+
+    fn difference_at<F>(this: &Rc<Self>, this_h: usize, that: &Rc<Self>, that_h: usize, cmp: &F) -> (Rc<Self>, usize)
+        where F: Fn(&E, &E) -> Ordering
+    {
+        if Rc::ptr_eq(this, that) {
+            return (Rc::new(BinaryTree::Empty), 0);
+        }
+        if this.is_empty() {
+            return (this.clone(), 0);
+        }
+        match **that {
+            BinaryTree::Empty => (this.clone(), this_h),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, that_h);
+                let (less, less_h, _, greater, greater_h) = BinaryTree::split_at(this, this_h, root, cmp);
+                let (new_left, left_h) = BinaryTree::difference_at(&less, less_h, left, height, cmp);
+                let (new_right, right_h) = BinaryTree::difference_at(&greater, greater_h, right, height, cmp);
+                BinaryTree::concat_at(new_left, left_h, new_right, right_h)
+            }
+        }
+    }
+
+    fn symmetric_difference_at<F>(this: &Rc<Self>, this_h: usize, that: &Rc<Self>, that_h: usize, cmp: &F) -> (Rc<Self>, usize)
+        where F: Fn(&E, &E) -> Ordering
+    {
+        if Rc::ptr_eq(this, that) {
+            return (Rc::new(BinaryTree::Empty), 0);
+        }
+        if that.is_empty() {
+            return (this.clone(), this_h);
+        }
+        match **this {
+            BinaryTree::Empty => (that.clone(), that_h),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, this_h);
+                let (less, less_h, found, greater, greater_h) = BinaryTree::split_at(that, that_h, root, cmp);
+                let (new_left, left_h) = BinaryTree::symmetric_difference_at(left, height, &less, less_h, cmp);
+                let (new_right, right_h) = BinaryTree::symmetric_difference_at(right, height, &greater, greater_h, cmp);
+                if found.is_some() {
+                    BinaryTree::concat_at(new_left, left_h, new_right, right_h)
+                } else if Rc::ptr_eq(&new_left, left) && Rc::ptr_eq(&new_right, right) {
+                    (this.clone(), this_h)
+                } else {
+                    BinaryTree::join_at(new_left, left_h, root.clone(), new_right, right_h)
+                }
+            }
+        }
+    }
+
+    /// Merges two trees by splitting and joining. `left` and `right` decide what happens to the
+    /// elements found only in `self` or only in `other`, `both` combines elements found in both
+    /// and removes them on `None`. `both` is called with the element of `self` first.
+    pub fn merge_with<L, R, B>(&self, other: &Self, left: &Only<L>, right: &Only<R>, both: &B) -> Self
+        where L: Fn(&E) -> Option<E>, R: Fn(&E) -> Option<E>, B: Fn(&E, &E) -> Option<E>, C: Default
+    {
+        self.merge_with_by(other, &Self::order(), left, right, both)
+    }
+
+    /// Like `merge_with`, ordering by `cmp`, which must agree with the tree's order.
+    pub fn merge_with_by<F, L, R, B>(&self, other: &Self, cmp: &F, left: &Only<L>, right: &Only<R>, both: &B) -> Self
+        where F: Fn(&E, &E) -> Ordering, L: Fn(&E) -> Option<E>, R: Fn(&E) -> Option<E>, B: Fn(&E, &E) -> Option<E>
+    {
+        let this = Rc::new(self.clone());
+        let that = Rc::new(other.clone());
+        BinaryTree::unwrap(BinaryTree::merge_at(&this, self.black_height(), &that, other.black_height(), cmp, &(left, right, both)).0)
+    }
+
+    //The callbacks travel together as (left, right, both)
+    fn merge_at<F, L, R, B>(this: &Rc<Self>, this_h: usize, that: &Rc<Self>, that_h: usize, cmp: &F,
+                            callbacks: &(&Only<L>, &Only<R>, &B)) -> (Rc<Self>, usize)
+        where F: Fn(&E, &E) -> Ordering, L: Fn(&E) -> Option<E>, R: Fn(&E) -> Option<E>, B: Fn(&E, &E) -> Option<E>
+    {
+        let (left_only, right_only, both) = *callbacks;
+        if that.is_empty() {
+            return BinaryTree::merge_one_side(this, this_h, left_only);
+        }
+        match **this {
+            BinaryTree::Empty => BinaryTree::merge_one_side(that, that_h, right_only),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, this_h);
+                let (less, less_h, found, greater, greater_h) = BinaryTree::split_at(that, that_h, root, cmp);
+                let (new_left, left_h) = BinaryTree::merge_at(left, height, &less, less_h, cmp, callbacks);
+                let (new_right, right_h) = BinaryTree::merge_at(right, height, &greater, greater_h, cmp, callbacks);
+                let merged = match (found, left_only) {
+                    (Some(found), _) => both(root, &found),
+                    (None, Only::Keep) if Rc::ptr_eq(&new_left, left) && Rc::ptr_eq(&new_right, right) =>
+                        return (this.clone(), this_h),
+                    (None, Only::Keep) => Some(root.clone()),
+                    (None, Only::Drop) => None,
+                    (None, Only::With(f)) => f(root),
+                };
+                match merged {
+                    Some(elem) => BinaryTree::join_at(new_left, left_h, elem, new_right, right_h),
+                    None => BinaryTree::concat_at(new_left, left_h, new_right, right_h),
+                }
+            }
+        }
+    }
+
+    fn merge_one_side<F>(tree: &Rc<Self>, height: usize, only: &Only<F>) -> (Rc<Self>, usize) where F: Fn(&E) -> Option<E> {
+        match *only {
+            Only::Keep => (tree.clone(), height),
+            Only::Drop => (Rc::new(BinaryTree::Empty), 0),
+            Only::With(ref f) => {
+                let tree = BinaryTree::from_sorted_vec(tree.iter().filter_map(f).collect());
+                let height = tree.black_height();
+                (Rc::new(tree), height)
+            }
+        }
+    }
+
+    /// The elements that satisfy `pred`, in O(n). Subtrees that lose nothing are shared.
+    pub fn filter<P>(&self, pred: P) -> Self where P: Fn(&E) -> bool {
+        let tree = Rc::new(self.clone());
+        BinaryTree::unwrap(BinaryTree::filter_at(&tree, self.black_height(), &pred).0)
+    }
+
+    fn filter_at<P>(tree: &Rc<Self>, height: usize, pred: &P) -> (Rc<Self>, usize) where P: Fn(&E) -> bool {
+        match **tree {
+            BinaryTree::Empty => (tree.clone(), 0),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let child_h = Self::child_height(color, height);
+                let (new_left, left_h) = BinaryTree::filter_at(left, child_h, pred);
+                let (new_right, right_h) = BinaryTree::filter_at(right, child_h, pred);
+                if !pred(root) {
+                    BinaryTree::concat_at(new_left, left_h, new_right, right_h)
+                } else if Rc::ptr_eq(&new_left, left) && Rc::ptr_eq(&new_right, right) {
+                    (tree.clone(), height)
+                } else {
+                    BinaryTree::join_at(new_left, left_h, root.clone(), new_right, right_h)
+                }
+            }
+        }
+    }
+
+    /// Splits the tree into the elements that satisfy `pred` and those that don't, in O(n).
+    pub fn partition<P>(&self, pred: P) -> (Self, Self) where P: Fn(&E) -> bool {
+        let tree = Rc::new(self.clone());
+        let ((yes, _), (no, _)) = BinaryTree::partition_at(&tree, self.black_height(), &pred);
+        (BinaryTree::unwrap(yes), BinaryTree::unwrap(no))
+    }
+
+    fn partition_at<P>(tree: &Rc<Self>, height: usize, pred: &P) -> ((Rc<Self>, usize), (Rc<Self>, usize))
+        where P: Fn(&E) -> bool
+    {
+        match **tree {
+            BinaryTree::Empty => ((tree.clone(), 0), (tree.clone(), 0)),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let child_h = Self::child_height(color, height);
+                let ((yes_left, yes_left_h), (no_left, no_left_h)) = BinaryTree::partition_at(left, child_h, pred);
+                let ((yes_right, yes_right_h), (no_right, no_right_h)) = BinaryTree::partition_at(right, child_h, pred);
+                if pred(root) {
+                    let yes = if Rc::ptr_eq(&yes_left, left) && Rc::ptr_eq(&yes_right, right) {
+                        (tree.clone(), height)
+                    } else {
+                        BinaryTree::join_at(yes_left, yes_left_h, root.clone(), yes_right, yes_right_h)
+                    };
+                    (yes, BinaryTree::concat_at(no_left, no_left_h, no_right, no_right_h))
+                } else {
+                    let no = if Rc::ptr_eq(&no_left, left) && Rc::ptr_eq(&no_right, right) {
+                        (tree.clone(), height)
+                    } else {
+                        BinaryTree::join_at(no_left, no_left_h, root.clone(), no_right, no_right_h)
+                    };
+                    (BinaryTree::concat_at(yes_left, yes_left_h, yes_right, yes_right_h), no)
+                }
+            }
+        }
+    }
+
+    /// Applies `f` to every element keeping the shape and colors of the tree, in O(n) with no
+    /// rebalancing. `f` must not change the relative order of the elements.
+    pub fn map_monotone<T, N, F>(&self, f: &F) -> BinaryTree<T, N, C>
+        where T: Clone, N: Measure<T>, C: Comparator<T>, F: Fn(&E) -> T
+    {
+        match *self {
+            BinaryTree::Empty => BinaryTree::Empty,
+            BinaryTree::Node(color, ref value, ref left, ref right, _, _, _) =>
+                BinaryTree::node(color, f(value), left.map_monotone(f), right.map_monotone(f)),
+        }
+    }
+
+    /// The elements that differ between `self` and `other`, in order. Elements present in both
+    /// are reported as `Changed` when `same` says they differ. Subtrees shared between the two
+    /// trees are skipped, so diffing two versions of a tree costs about O(edits * log n).
+    /// The trees are compared lazily, stopping early skips the rest of the work.
+    /// `cmp` must agree with the tree's order.
+    pub fn diff_by<F, S>(&self, other: &Self, cmp: F, same: S) -> DiffIter<E, M, C, F, S>
+        where F: Fn(&E, &E) -> Ordering, S: Fn(&E, &E) -> bool
+    {
+        let this = Rc::new(self.clone());
+        let that = if ptr::eq(self, other) { this.clone() } else { Rc::new(other.clone()) };
+        let pending = vec![Pending::Pair(this, self.black_height(), that, other.black_height())];
+        DiffIter { pending, cmp, same }
+    }
+
+    pub fn diff(&self, other: &Self) -> impl Iterator<Item=Diff<E>> where E: PartialEq, C: Default {
+        self.diff_by(other, Self::order(), |a: &E, b: &E| a == b)
+    }
+
+    //The internal tree surgery below works on Rc'd subtrees so untouched subtrees are shared, not copied.
+    //Black heights are threaded through so each join only pays for the difference in heights.
+    fn split_at<Q: ?Sized, F>(tree: &Rc<Self>, height: usize, key: &Q, cmp: &F) -> (Rc<Self>, usize, Option<E>, Rc<Self>, usize)
+        where F: Fn(&E, &Q) -> Ordering
+    {
+        match **tree {
+            BinaryTree::Empty => (tree.clone(), 0, None, tree.clone(), 0),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, height);
+                match cmp(root, key) {
+                    Ordering::Greater => {
+                        let (less, less_h, found, rest, rest_h) = BinaryTree::split_at(left, height, key, cmp);
+                        let (greater, greater_h) =
+                            BinaryTree::join_at(rest, rest_h, root.clone(), right.clone(), height);
+                        (less, less_h, found, greater, greater_h)
+                    }
+                    Ordering::Less => {
+                        let (rest, rest_h, found, greater, greater_h) = BinaryTree::split_at(right, height, key, cmp);
+                        let (less, less_h) =
+                            BinaryTree::join_at(left.clone(), height, root.clone(), rest, rest_h);
+                        (less, less_h, found, greater, greater_h)
+                    }
+                    Ordering::Equal =>
+                        (left.clone(), height, Some(root.clone()), right.clone(), height),
+                }
+            }
+        }
+    }
+
+    fn split_last_at(tree: &Rc<Self>, height: usize) -> (Rc<Self>, usize, E) {
+        match **tree {
+            BinaryTree::Empty => panic!("split_last_at() called on empty tree"),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, height);
+                if right.is_empty() {
+                    (left.clone(), height, root.clone())
+                } else {
+                    let (rest, rest_h, last) = BinaryTree::split_last_at(right, height);
+                    let (tree, tree_h) = BinaryTree::join_at(left.clone(), height, root.clone(), rest, rest_h);
+                    (tree, tree_h, last)
+                }
+            }
+        }
+    }
+
+    fn split_first_at(tree: &Rc<Self>, height: usize) -> (E, Rc<Self>, usize) {
+        match **tree {
+            BinaryTree::Empty => panic!("split_first_at() called on empty tree"),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = Self::child_height(color, height);
+                if left.is_empty() {
+                    (root.clone(), right.clone(), height)
+                } else {
+                    let (first, rest, rest_h) = BinaryTree::split_first_at(left, height);
+                    let (tree, tree_h) = BinaryTree::join_at(rest, rest_h, root.clone(), right.clone(), height);
+                    (first, tree, tree_h)
+                }
+            }
+        }
+    }
+
+    fn concat_at(left: Rc<Self>, left_h: usize, right: Rc<Self>, right_h: usize) -> (Rc<Self>, usize) {
+        if left.is_empty() {
+            return (right, right_h);
+        }
+        if right.is_empty() {
+            return (left, left_h);
+        }
+        let (rest, rest_h, last) = BinaryTree::split_last_at(&left, left_h);
+        BinaryTree::join_at(rest, rest_h, last, right, right_h)
+    }
+
+    fn join_at(left: Rc<Self>, left_h: usize, mid: E, right: Rc<Self>, right_h: usize) -> (Rc<Self>, usize) {
+        //Painting the roots black up front means a red node is only ever placed above black subtrees
+        let (left, left_h) = BinaryTree::blacken(left, left_h);
+        let (right, right_h) = BinaryTree::blacken(right, right_h);
+        if left_h > right_h {
+            let tree = BinaryTree::join_right(&left, left_h, mid, right, right_h);
+            if tree.doubled_right() { (Rc::new(tree.paint(Color::Black)), left_h + 1) }
+            else { (Rc::new(tree), left_h) }
+        }
+        else if left_h < right_h {
+            let tree = BinaryTree::join_left(&right, right_h, left, mid, left_h);
+            if tree.doubled_left() { (Rc::new(tree.paint(Color::Black)), right_h + 1) }
+            else { (Rc::new(tree), right_h) }
+        }
+        else {
+            (Rc::new(BinaryTree::branch(Color::Red, mid, left, right)), left_h)
+        }
+    }
+
+    fn blacken(tree: Rc<Self>, height: usize) -> (Rc<Self>, usize) {
+        if tree.is_black() { (tree, height) }
+        else { (Rc::new(tree.paint(Color::Black)), height + 1) }
+    }
+
+    //Walks the right spine until it finds a black subtree as tall as right
+    fn join_right(tree: &Rc<Self>, height: usize, mid: E, right: Rc<Self>, right_h: usize) -> Self {
+        if height == right_h && tree.is_black() {
+            return BinaryTree::branch(Color::Red, mid, tree.clone(), right);
+        }
+        match **tree {
+            BinaryTree::Empty => panic!("join_right() ran off the end of the tree"),
+            BinaryTree::Node(color, ref root, ref left, ref sub, _, _, _) => {
+                let height = Self::child_height(color, height);
+                let joined = BinaryTree::join_right(sub, height, mid, right, right_h);
+                if color == Color::Black && joined.doubled_right() {
+                    BinaryTree::node(Color::Red
+                        , joined.value()
+                        , BinaryTree::branch(Color::Black, root.clone(), left.clone(), joined.left())
+                        , joined.right().paint(Color::Black)
+                        )
+                } else {
+                    BinaryTree::branch(color, root.clone(), left.clone(), Rc::new(joined))
                 }
-                else if root < val.borrow() {
-                    BinaryTree::Node(
-                        color.clone(),
-                        root.clone(),
-                        left.clone(),
-                        Rc::new(right.delete(val)))
+            }
+        }
+    }
+
+    //Walks the left spine until it finds a black subtree as tall as left
+    fn join_left(tree: &Rc<Self>, height: usize, left: Rc<Self>, mid: E, left_h: usize) -> Self {
+        if height == left_h && tree.is_black() {
+            return BinaryTree::branch(Color::Red, mid, left, tree.clone());
+        }
+        match **tree {
+            BinaryTree::Empty => panic!("join_left() ran off the end of the tree"),
+            BinaryTree::Node(color, ref root, ref sub, ref right, _, _, _) => {
+                let height = Self::child_height(color, height);
+                let joined = BinaryTree::join_left(sub, height, left, mid, left_h);
+                if color == Color::Black && joined.doubled_left() {
+                    BinaryTree::node(Color::Red
+                        , joined.value()
+                        , joined.left().paint(Color::Black)
+                        , BinaryTree::branch(Color::Black, root.clone(), joined.right(), right.clone())
+                        )
                 } else {
-                    self.del()
+                    BinaryTree::branch(color, root.clone(), Rc::new(joined), right.clone())
                 }
             }
         }
     }
 
+    #[inline]
+    fn child_height(color: Color, height: usize) -> usize {
+        if color == Color::Black { height - 1 } else { height }
+    }
+
+    #[inline]
+    fn unwrap(tree: Rc<Self>) -> Self {
+        Rc::try_unwrap(tree).unwrap_or_else(|tree| tree.deref().clone())
+    }
 
     pub fn inorder_successor(&self) -> Self {
         if self.right().is_empty() { BinaryTree::Empty }
@@ -141,6 +930,94 @@ impl<E> BinaryTree<E> where E: Clone + Eq + Ord {
         if self.left().is_empty() { self.clone() }
         else { self.left().minimum() }
     }
+
+    pub fn first(&self) -> Option<&E> {
+        match *self {
+            BinaryTree::Empty => None,
+            BinaryTree::Node(_, ref value, ref left, _, _, _, _) => left.first().or(Some(value)),
+        }
+    }
+
+    pub fn last(&self) -> Option<&E> {
+        match *self {
+            BinaryTree::Empty => None,
+            BinaryTree::Node(_, ref value, _, ref right, _, _, _) => right.last().or(Some(value)),
+        }
+    }
+
+    /// Removes the smallest element, returning it along with the rest of the tree.
+    pub fn pop_first(&self) -> Option<(E, Self)> {
+        if self.is_empty() {
+            return None;
+        }
+        let (first, rest, _) = BinaryTree::split_first_at(&Rc::new(self.clone()), self.black_height());
+        Some((first, BinaryTree::unwrap(rest)))
+    }
+
+    /// Removes the largest element, returning it along with the rest of the tree.
+    pub fn pop_last(&self) -> Option<(E, Self)> {
+        if self.is_empty() {
+            return None;
+        }
+        let (rest, _, last) = BinaryTree::split_last_at(&Rc::new(self.clone()), self.black_height());
+        Some((last, BinaryTree::unwrap(rest)))
+    }
+
+    /// The largest element less than or equal to `key`.
+    pub fn floor<Q: ?Sized>(&self, key: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> + Default {
+        self.floor_by(key, &Self::borrowed_order())
+    }
+
+    /// The smallest element greater than or equal to `key`.
+    pub fn ceiling<Q: ?Sized>(&self, key: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> + Default {
+        self.ceiling_by(key, &Self::borrowed_order())
+    }
+
+    /// The largest element strictly less than `key`.
+    pub fn predecessor<Q: ?Sized>(&self, key: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> + Default {
+        self.predecessor_by(key, &Self::borrowed_order())
+    }
+
+    /// The smallest element strictly greater than `key`.
+    pub fn successor<Q: ?Sized>(&self, key: &Q) -> Option<&E> where E: Borrow<Q>, C: Comparator<Q> + Default {
+        self.successor_by(key, &Self::borrowed_order())
+    }
+
+    pub fn floor_by<Q: ?Sized, F>(&self, key: &Q, cmp: &F) -> Option<&E> where F: Fn(&E, &Q) -> Ordering {
+        match *self {
+            BinaryTree::Empty => None,
+            BinaryTree::Node(_, ref value, ref left, ref right, _, _, _) =>
+                if cmp(value, key) == Ordering::Greater { left.floor_by(key, cmp) }
+                else { right.floor_by(key, cmp).or(Some(value)) }
+        }
+    }
+
+    pub fn ceiling_by<Q: ?Sized, F>(&self, key: &Q, cmp: &F) -> Option<&E> where F: Fn(&E, &Q) -> Ordering {
+        match *self {
+            BinaryTree::Empty => None,
+            BinaryTree::Node(_, ref value, ref left, ref right, _, _, _) =>
+                if cmp(value, key) == Ordering::Less { right.ceiling_by(key, cmp) }
+                else { left.ceiling_by(key, cmp).or(Some(value)) }
+        }
+    }
+
+    pub fn predecessor_by<Q: ?Sized, F>(&self, key: &Q, cmp: &F) -> Option<&E> where F: Fn(&E, &Q) -> Ordering {
+        match *self {
+            BinaryTree::Empty => None,
+            BinaryTree::Node(_, ref value, ref left, ref right, _, _, _) =>
+                if cmp(value, key) != Ordering::Less { left.predecessor_by(key, cmp) }
+                else { right.predecessor_by(key, cmp).or(Some(value)) }
+        }
+    }
+
+    pub fn successor_by<Q: ?Sized, F>(&self, key: &Q, cmp: &F) -> Option<&E> where F: Fn(&E, &Q) -> Ordering {
+        match *self {
+            BinaryTree::Empty => None,
+            BinaryTree::Node(_, ref value, ref left, ref right, _, _, _) =>
+                if cmp(value, key) != Ordering::Greater { right.successor_by(key, cmp) }
+                else { left.successor_by(key, cmp).or(Some(value)) }
+        }
+    }
     /*pub fn delete(&self, val: E) -> Self {
         self.del(val).paint(Color::Black)
     }
@@ -168,7 +1045,7 @@ impl<E> BinaryTree<E> where E: Clone + Eq + Ord {
         }
     }*/
 
-    fn balance(c:Color, val:E, left: BinaryTree<E>, right: BinaryTree<E>) -> Self {
+    fn balance(c:Color, val:E, left: BinaryTree<E, M, C>, right: BinaryTree<E, M, C>) -> Self {
         match c {
             Color::Red => BinaryTree::node(c, val, left, right),
             Color::Black => {
@@ -177,27 +1054,27 @@ impl<E> BinaryTree<E> where E: Clone + Eq + Ord {
                     BinaryTree::node(Color::Red
                         , left.value()
                         , left.left().paint(Color::Black)
-                        , BinaryTree::Node(Color::Black, val, left.right(), Rc::new(right))
+                        , BinaryTree::branch(Color::Black, val, left.right(), Rc::new(right))
                         )
                 } else if left.doubled_right() {
                     //println!("left double_right\n{:?}", right);
                     BinaryTree::node(Color::Red
                         , left.right().value()
-                        , BinaryTree::Node(Color::Black, left.value(), left.left(), left.right().left())
-                        , BinaryTree::Node(Color::Black, val, left.right().right(), Rc::new(right))
+                        , BinaryTree::branch(Color::Black, left.value(), left.left(), left.right().left())
+                        , BinaryTree::branch(Color::Black, val, left.right().right(), Rc::new(right))
                         )
                 } else if right.doubled_left() {
                     //println!("right double_left\n{:?}", right);
                     BinaryTree::node(Color::Red
                         , right.left().value()
-                        , BinaryTree::Node(Color::Black, val, Rc::new(left), right.left().left())
-                        , BinaryTree::Node(Color::Black, right.value(), right.left().right(), right.right())
+                        , BinaryTree::branch(Color::Black, val, Rc::new(left), right.left().left())
+                        , BinaryTree::branch(Color::Black, right.value(), right.left().right(), right.right())
                         )
                 } else if right.doubled_right() {
                     //println!("right double_right\n{:?}", right);
                     BinaryTree::node(Color::Red
                         , right.value()
-                        , BinaryTree::Node(Color::Black, val, Rc::new(left), right.left())
+                        , BinaryTree::branch(Color::Black, val, Rc::new(left), right.left())
                         , right.right().paint(Color::Black)
                         )
                 } else {
@@ -224,63 +1101,98 @@ impl<E> BinaryTree<E> where E: Clone + Eq + Ord {
     pub fn is_empty(&self) -> bool {
         match self {
             &BinaryTree::Empty => true,
-            &BinaryTree::Node(_, _, _, _) => false,
+            &BinaryTree::Node(_, _, _, _, _, _, _) => false,
         }
     }
 
     pub fn is_leaf(&self) -> bool {
-        match self {
-            &BinaryTree::Empty => false,
-            &BinaryTree::Node(_, _, ref left, ref right) => left.is_empty() && right.is_empty(),
+        match *self {
+            BinaryTree::Empty => false,
+            BinaryTree::Node(_, _, ref left, ref right, _, _, _) => left.is_empty() && right.is_empty(),
         }
     }
 
     pub fn paint(&self, c:Color) -> Self {
         match self {
             &BinaryTree::Empty => panic!("paint() called on empty tree"),
-            &BinaryTree::Node(_, ref value, ref left, ref right) => BinaryTree::Node(c, value.clone(), left.clone(), right.clone()),
+            &BinaryTree::Node(_, ref value, ref left, ref right, ref measure, size, _) => BinaryTree::Node(c, value.clone(), left.clone(), right.clone(), measure.clone(), size, PhantomData),
         }
     }
 
     pub fn color(&self) -> Color {
         match self {
             &BinaryTree::Empty => panic!("color() called on empty tree"),
-            &BinaryTree::Node(color, _, _, _) => color,
+            &BinaryTree::Node(color, _, _, _, _, _, _) => color,
         }
     }
     pub fn value(&self) -> E {
-        match self {
-            &BinaryTree::Empty => panic!("value() called on empty tree"),
-            &BinaryTree::Node(_, ref value, _, _) => value.clone(),
+        match *self {
+            BinaryTree::Empty => panic!("value() called on empty tree"),
+            BinaryTree::Node(_, ref value, _, _, _, _, _) => value.clone(),
         }
     }
-    pub fn left(&self) -> Rc<BinaryTree<E>> {
-        match self {
-            &BinaryTree::Empty => panic!("left() called on empty tree"),
-            &BinaryTree::Node(_, _, ref left, _) => left.clone(),
+    pub fn left(&self) -> Rc<BinaryTree<E, M, C>> {
+        match *self {
+            BinaryTree::Empty => panic!("left() called on empty tree"),
+            BinaryTree::Node(_, _, ref left, _, _, _, _) => left.clone(),
         }
     }
-    pub fn right(&self) -> Rc<BinaryTree<E>> {
-        match self {
-            &BinaryTree::Empty => panic!("right() called on empty tree"),
-            &BinaryTree::Node(_, _, _, ref right) => right.clone(),
+    pub fn right(&self) -> Rc<BinaryTree<E, M, C>> {
+        match *self {
+            BinaryTree::Empty => panic!("right() called on empty tree"),
+            BinaryTree::Node(_, _, _, ref right, _, _, _) => right.clone(),
         }
     }
+    /// Borrows the elements in order.
+    pub fn iter(&self) -> InOrder<'_, E, M, C> {
+        let mut iter = InOrder { stack: vec![] };
+        iter.push_left(self);
+        iter
+    }
+
+    /// The number of elements, in O(1).
     pub fn length(&self) -> usize {
-        match self {
-            &BinaryTree::Empty => 0,
-            &BinaryTree::Node(_, _, ref left, ref right) => 1 + left.length() + right.length(),
+        match *self {
+            BinaryTree::Empty => 0,
+            BinaryTree::Node(_, _, _, _, _, size, _) => size,
         }
     }
     pub fn height(&self) -> usize {
-        match self {
-            &BinaryTree::Empty => 0,
-            &BinaryTree::Node(_, _, ref left, ref right) => 1 + max(left.height(), right.height()),
+        match *self {
+            BinaryTree::Empty => 0,
+            BinaryTree::Node(_, _, ref left, ref right, _, _, _) => 1 + max(left.height(), right.height()),
+        }
+    }
+    pub fn black_height(&self) -> usize {
+        match *self {
+            BinaryTree::Empty => 0,
+            BinaryTree::Node(color, _, ref left, _, _, _, _) =>
+                if color == Color::Black { 1 + left.black_height() } else { left.black_height() },
+        }
+    }
+
+    //Empty leaves count as black
+    pub fn is_black(&self) -> bool {
+        match *self {
+            BinaryTree::Empty => true,
+            BinaryTree::Node(color, _, _, _, _, _, _) => color == Color::Black,
+        }
+    }
+}
+
+impl<E: Clone, M: Clone, C> Clone for BinaryTree<E, M, C> {
+    fn clone(&self) -> Self {
+        match *self {
+            BinaryTree::Empty => BinaryTree::Empty,
+            BinaryTree::Node(color, ref value, ref left, ref right, ref measure, size, _) =>
+                BinaryTree::Node(color, value.clone(), left.clone(), right.clone(), measure.clone(), size, PhantomData),
         }
     }
 }
 
-impl<E:PartialEq> PartialEq for BinaryTree<E> {
+impl<E: Eq, M, C> Eq for BinaryTree<E, M, C> {}
+
+impl<E:PartialEq, M, C> PartialEq for BinaryTree<E, M, C> {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (&BinaryTree::Empty, &BinaryTree::Empty) =>
@@ -292,7 +1204,7 @@ impl<E:PartialEq> PartialEq for BinaryTree<E> {
             (&BinaryTree::Empty, _) =>
                 false,
 
-            (&BinaryTree::Node(_, ref a_root, ref a_left, ref a_right), &BinaryTree::Node(_, ref b_root, ref b_left, ref b_right)) =>
+            (BinaryTree::Node(_, a_root, a_left, a_right, _, _, _), BinaryTree::Node(_, b_root, b_left, b_right, _, _, _)) =>
                 (a_root == b_root) && (a_left == b_left) && (a_right == b_right)
         }
     }
@@ -303,31 +1215,28 @@ impl<E:PartialEq> PartialEq for BinaryTree<E> {
 
 }
 
-impl<E: Clone + Debug> Debug for BinaryTree<E>{
+impl<E: Clone + Debug, M, C> Debug for BinaryTree<E, M, C>{
     fn fmt(&self, f: &mut Formatter) -> Result {
-        match self {
-            &BinaryTree::Empty => write!(f, "Empty"),
-            &BinaryTree::Node(ref color, ref value, ref left, ref right) => write!(f, "Node({:?}, {:?}, {:?}, {:?})", color, value.clone(), left.clone(), right.clone()),
+        match *self {
+            BinaryTree::Empty => write!(f, "Empty"),
+            BinaryTree::Node(ref color, ref value, ref left, ref right, _, _, _) => write!(f, "Node({:?}, {:?}, {:?}, {:?})", color, value.clone(), left.clone(), right.clone()),
         }
 
     }
 }
 
 
-impl<E: Clone + Ord + Eq> IntoIterator for BinaryTree<E> {
+impl<E: Clone, M: Measure<E>, C: Comparator<E>> IntoIterator for BinaryTree<E, M, C> {
     type Item = E;
-    type IntoIter = Iter<E>;
+    type IntoIter = Iter<E, M, C>;
 
     #[inline]
     fn into_iter(self) -> Self::IntoIter {
-        let root = Rc::new(self);
-        Iter {
-            stack: Rc::new(list![root.clone()]),
-        }
+        Iter::new(Rc::new(self))
     }
 }
 
-impl<E: Clone + Ord + Eq> FromIterator<E> for BinaryTree<E> {
+impl<E: Clone, M: Measure<E>, C: Comparator<E> + Default> FromIterator<E> for BinaryTree<E, M, C> {
     fn from_iter<I: IntoIterator<Item=E>>(iterator: I) -> Self {
         iterator
             .into_iter()
@@ -335,7 +1244,24 @@ impl<E: Clone + Ord + Eq> FromIterator<E> for BinaryTree<E> {
     }
 }
 
-impl<E: Clone + Ord + Eq> Iterator for Iter<E> {
+impl<E: Clone, M: Measure<E>, C: Comparator<E>> Iter<E, M, C> {
+    fn new(tree: Rc<BinaryTree<E, M, C>>) -> Self {
+        let mut iter = Iter { stack: Rc::new(List::empty()) };
+        iter.push_left(tree);
+        iter
+    }
+
+    fn push_left(&mut self, mut tree: Rc<BinaryTree<E, M, C>>) {
+        while !tree.is_empty() {
+            let left = tree.left();
+            self.stack = List::cons(tree, self.stack.clone()).into();
+            tree = left;
+        }
+    }
+}
+
+//Yields the elements in order, like InOrder but cloning them out of the shared nodes
+impl<E: Clone, M: Measure<E>, C: Comparator<E>> Iterator for Iter<E, M, C> {
     type Item = E;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -344,13 +1270,81 @@ impl<E: Clone + Ord + Eq> Iterator for Iter<E> {
         }
         let node = self.stack.head();
         self.stack = self.stack.tail();
-        if !node.right().is_empty() {
-            self.stack = List::cons(node.right(), self.stack.clone()).into();
+        self.push_left(node.right());
+        Some(node.value())
+    }
+}
+
+impl<'a, E, M, C> InOrder<'a, E, M, C> {
+    fn push_left(&mut self, mut tree: &'a BinaryTree<E, M, C>) {
+        while let BinaryTree::Node(_, _, ref left, _, _, _, _) = *tree {
+            self.stack.push(tree);
+            tree = left;
+        }
+    }
+}
+
+impl<'a, E, M, C> Iterator for InOrder<'a, E, M, C> {
+    type Item = &'a E;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.stack.pop() {
+            Some(BinaryTree::Node(_, value, _, right, _, _, _)) => {
+                self.push_left(right);
+                Some(value)
+            }
+            _ => None,
         }
-        if !node.left().is_empty() {
-            self.stack = List::cons(node.left(), self.stack.clone()).into();
+    }
+}
+
+impl<E, M, C, F, S> DiffIter<E, M, C, F, S>
+    where E: Clone, M: Measure<E>, C: Comparator<E>, F: Fn(&E, &E) -> Ordering, S: Fn(&E, &E) -> bool
+{
+    //Splits `that` around the root of `this` like the set operations, pushing the right halves,
+    //the root and the left halves so they come off the stack in order
+    fn expand(&mut self, this: Rc<BinaryTree<E, M, C>>, this_h: usize, that: Rc<BinaryTree<E, M, C>>, that_h: usize) {
+        if Rc::ptr_eq(&this, &that) {
+            return;
+        }
+        match *this {
+            BinaryTree::Empty => self.pending.push(Pending::Added(Iter::new(that))),
+            BinaryTree::Node(..) if that.is_empty() => self.pending.push(Pending::Removed(Iter::new(this.clone()))),
+            BinaryTree::Node(color, ref root, ref left, ref right, _, _, _) => {
+                let height = BinaryTree::<E, M, C>::child_height(color, this_h);
+                let (less, less_h, found, greater, greater_h) = BinaryTree::split_at(&that, that_h, root, &self.cmp);
+                self.pending.push(Pending::Pair(right.clone(), height, greater, greater_h));
+                match found {
+                    None => self.pending.push(Pending::Found(Diff::Removed(root.clone()))),
+                    Some(ref found) if (self.same)(root, found) => {},
+                    Some(found) => self.pending.push(Pending::Found(Diff::Changed(root.clone(), found))),
+                }
+                self.pending.push(Pending::Pair(left.clone(), height, less, less_h));
+            }
+        }
+    }
+}
+
+impl<E, M, C, F, S> Iterator for DiffIter<E, M, C, F, S>
+    where E: Clone, M: Measure<E>, C: Comparator<E>, F: Fn(&E, &E) -> Ordering, S: Fn(&E, &E) -> bool
+{
+    type Item = Diff<E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.pending.pop()? {
+                Pending::Pair(this, this_h, that, that_h) => self.expand(this, this_h, that, that_h),
+                Pending::Found(change) => return Some(change),
+                Pending::Added(mut iter) => if let Some(elem) = iter.next() {
+                    self.pending.push(Pending::Added(iter));
+                    return Some(Diff::Added(elem));
+                },
+                Pending::Removed(mut iter) => if let Some(elem) = iter.next() {
+                    self.pending.push(Pending::Removed(iter));
+                    return Some(Diff::Removed(elem));
+                },
+            }
         }
-        return Some(node.value());
     }
 }
 
@@ -400,5 +1394,389 @@ fn tree_iter() {
             .map(|x| x * 2)
             .collect();
 
-    assert_eq!(list, binary_tree![8, 10, 4, 2, 6])
+    assert_eq!(list, binary_tree![8, 10, 4, 2, 6]);
+
+    let tree: BinaryTree<i32> = (0..100).rev().collect();
+    assert_eq!(tree.iter().cloned().collect::<Vec<_>>(), (0..100).collect::<Vec<_>>());
+    assert_eq!(BinaryTree::<i32>::empty().iter().next(), None);
+}
+
+#[cfg(test)]
+fn in_order<E: Clone, M, C>(tree: &BinaryTree<E, M, C>) -> Vec<E> {
+    match *tree {
+        BinaryTree::Empty => vec![],
+        BinaryTree::Node(_, ref value, ref left, ref right, _, _, _) => {
+            let mut elems = in_order(left);
+            elems.push(value.clone());
+            elems.extend(in_order(right));
+            elems
+        }
+    }
+}
+
+//Returns the black height after checking that no red node has a red child
+//and every path to a leaf passes through the same number of black nodes
+#[cfg(test)]
+fn assert_red_black<E, M, C>(tree: &BinaryTree<E, M, C>) -> usize {
+    match *tree {
+        BinaryTree::Empty => 0,
+        BinaryTree::Node(color, _, ref left, ref right, _, size, _) => {
+            if color == Color::Red {
+                for child in [left, right].iter() {
+                    if let BinaryTree::Node(child_color, _, _, _, _, _, _) = ***child {
+                        assert!(child_color != Color::Red, "red node has a red child");
+                    }
+                }
+            }
+            let child_size = |child: &BinaryTree<E, M, C>| match *child {
+                BinaryTree::Empty => 0,
+                BinaryTree::Node(_, _, _, _, _, size, _) => size,
+            };
+            assert_eq!(size, child_size(left) + 1 + child_size(right), "cached size is stale");
+            let left_h = assert_red_black(left);
+            assert_eq!(left_h, assert_red_black(right), "black heights differ");
+            if color == Color::Black { left_h + 1 } else { left_h }
+        }
+    }
+}
+
+#[test]
+fn tree_split() {
+    let tree: BinaryTree<i32> = (0..100).collect();
+
+    let (less, found, greater) = tree.split(&37);
+    assert_red_black(&less);
+    assert_red_black(&greater);
+    assert_eq!(found, Some(37));
+    assert_eq!(in_order(&less), (0..37).collect::<Vec<_>>());
+    assert_eq!(in_order(&greater), (38..100).collect::<Vec<_>>());
+
+    let (less, found, greater) = tree.split(&-1);
+    assert_eq!(found, None);
+    assert!(less.is_empty());
+    assert_eq!(in_order(&greater), in_order(&tree));
+}
+
+#[test]
+fn tree_join() {
+    for &(mid, end) in [(0, 1), (3, 200), (150, 200), (199, 200)].iter() {
+        let left: BinaryTree<i32> = (0..mid).collect();
+        let right: BinaryTree<i32> = (mid + 1..end).collect();
+
+        let joined = BinaryTree::join(left.clone(), mid, right.clone());
+        assert_red_black(&joined);
+        assert_eq!(in_order(&joined), (0..end).collect::<Vec<_>>());
+
+        let concatenated = BinaryTree::concat(left, right);
+        assert_red_black(&concatenated);
+        assert_eq!(in_order(&concatenated), (0..end).filter(|&x| x != mid).collect::<Vec<_>>());
+    }
+}
+
+#[test]
+fn tree_delete() {
+    let mut tree: BinaryTree<i32> = (0..64).collect();
+    let mut expected: Vec<i32> = (0..64).collect();
+    for x in (0..64).map(|x| (x * 37) % 64) {
+        tree = tree.delete(x);
+        expected.retain(|&y| y != x);
+        assert_red_black(&tree);
+        assert_eq!(in_order(&tree), expected);
+    }
+    assert!(tree.is_empty());
+}
+
+#[test]
+fn tree_set_operations() {
+    let evens: BinaryTree<i32> = (0..100).filter(|x| x % 2 == 0).collect();
+    let triples: BinaryTree<i32> = (0..100).filter(|x| x % 3 == 0).collect();
+
+    let union = evens.union(&triples);
+    assert_red_black(&union);
+    assert_eq!(in_order(&union), (0..100).filter(|x| x % 2 == 0 || x % 3 == 0).collect::<Vec<_>>());
+
+    let intersection = evens.intersection(&triples);
+    assert_red_black(&intersection);
+    assert_eq!(in_order(&intersection), (0..100).filter(|x| x % 6 == 0).collect::<Vec<_>>());
+
+    let difference = evens.difference(&triples);
+    assert_red_black(&difference);
+    assert_eq!(in_order(&difference), (0..100).filter(|x| x % 2 == 0 && x % 3 != 0).collect::<Vec<_>>());
+
+    let symmetric = evens.symmetric_difference(&triples);
+    assert_red_black(&symmetric);
+    assert_eq!(in_order(&symmetric), (0..100).filter(|x| (x % 2 == 0) != (x % 3 == 0)).collect::<Vec<_>>());
+}
+
+#[test]
+fn tree_set_operations_share_subtrees() {
+    let tree: BinaryTree<i32> = (0..100).collect();
+    let copy = tree.clone();
+
+    let union = tree.union(&copy);
+    assert!(Rc::ptr_eq(&union.left(), &tree.left()));
+    assert!(Rc::ptr_eq(&union.right(), &tree.right()));
+
+    let intersection = tree.intersection(&copy);
+    assert!(Rc::ptr_eq(&intersection.left(), &tree.left()));
+    assert!(tree.difference(&copy).is_empty());
+    assert!(tree.symmetric_difference(&copy).is_empty());
+
+    //Nothing from tree is missing from bigger, so bigger comes back without being rebuilt
+    let bigger = tree.insert(1000);
+    let union = bigger.union(&tree);
+    assert!(Rc::ptr_eq(&union.left(), &bigger.left()));
+    assert!(Rc::ptr_eq(&union.right(), &bigger.right()));
+    assert_eq!(in_order(&bigger.difference(&tree)), vec![1000]);
+}
+
+#[test]
+fn tree_from_sorted_iter() {
+    for len in 0..70 {
+        let tree = BinaryTree::<i32>::from_sorted_iter(0..len).unwrap();
+        assert_red_black(&tree);
+        assert!(tree.is_black());
+        assert_eq!(in_order(&tree), (0..len).collect::<Vec<_>>());
+    }
+
+    assert_eq!(BinaryTree::<i32>::from_sorted_iter(vec![1, 2, 4, 3]), Err(SortedError::OutOfOrder(3)));
+    assert_eq!(BinaryTree::<i32>::from_sorted_iter(vec![1, 2, 2, 3]), Err(SortedError::Duplicate(2)));
+
+    let tree = BinaryTree::<i32>::from_sorted_iter_unchecked(vec![1, 2, 3]);
+    assert_eq!(in_order(&tree), vec![1, 2, 3]);
+}
+
+#[test]
+fn tree_measure() {
+    use tree::measure::{Count, Sum};
+
+    let mut tree: BinaryTree<i32, Sum<i32>> = (0..100).collect();
+    assert_eq!(tree.summary(), Sum(4950));
+    for x in (0..100).filter(|x| x % 3 == 0) {
+        tree = tree.delete(x);
+    }
+    tree = tree.insert(1000);
+    let expected: Vec<i32> = (0..100).filter(|x| x % 3 != 0).chain(Some(1000)).collect();
+    assert_eq!(tree.summary(), Sum(expected.iter().sum()));
+
+    for &(lo, hi) in [(0, 100), (10, 20), (11, 12), (50, 2000), (30, 30)].iter() {
+        let sum = expected.iter().filter(|&&x| lo <= x && x < hi).sum();
+        assert_eq!(tree.measure_range(Bound::Included(&lo), Bound::Excluded(&hi)), Sum(sum));
+        let sum = expected.iter().filter(|&&x| lo < x && x <= hi).sum();
+        assert_eq!(tree.measure_range(Bound::Excluded(&lo), Bound::Included(&hi)), Sum(sum));
+    }
+
+    let (less, _, greater) = tree.split(&50);
+    assert_eq!(less.summary().0 + greater.summary().0, tree.summary().0 - 50);
+
+    let counted: BinaryTree<i32, Count> = (0..37).collect();
+    assert_eq!(counted.measure_range(Bound::Included(&5), Bound::Unbounded), Count(32));
+}
+
+#[test]
+fn tree_neighbors() {
+    let tree: BinaryTree<i32> = (0..50).map(|x| x * 2).collect();
+    assert_eq!(tree.first(), Some(&0));
+    assert_eq!(tree.last(), Some(&98));
+
+    assert_eq!(tree.floor(&7), Some(&6));
+    assert_eq!(tree.floor(&8), Some(&8));
+    assert_eq!(tree.floor(&-1), None);
+    assert_eq!(tree.ceiling(&7), Some(&8));
+    assert_eq!(tree.ceiling(&8), Some(&8));
+    assert_eq!(tree.ceiling(&99), None);
+    assert_eq!(tree.predecessor(&8), Some(&6));
+    assert_eq!(tree.predecessor(&0), None);
+    assert_eq!(tree.successor(&8), Some(&10));
+    assert_eq!(tree.successor(&98), None);
+
+    let (first, rest) = tree.pop_first().unwrap();
+    assert_eq!(first, 0);
+    assert_red_black(&rest);
+    assert_eq!(in_order(&rest), (1..50).map(|x| x * 2).collect::<Vec<_>>());
+
+    let (last, rest) = rest.pop_last().unwrap();
+    assert_eq!(last, 98);
+    assert_red_black(&rest);
+    assert_eq!(rest.first(), Some(&2));
+    assert_eq!(rest.last(), Some(&96));
+    assert!(BinaryTree::<i32>::empty().pop_first().is_none());
+}
+
+#[test]
+fn tree_comparator() {
+    use tree::comparator::ReverseOrder;
+    use tree::measure::Sum;
+
+    let tree: BinaryTree<i32, Sum<i32>, ReverseOrder> = (0..40).collect();
+    assert_red_black(&tree);
+    assert_eq!(in_order(&tree), (0..40).rev().collect::<Vec<_>>());
+    assert_eq!(tree.first(), Some(&39));
+    assert_eq!(tree.get(&7), Some(7));
+    assert_eq!(tree.floor(&7), Some(&7));
+    assert_eq!(tree.predecessor(&7), Some(&8));
+    assert_eq!(tree.measure_range(Bound::Included(&3), Bound::Unbounded), Sum(6));
+
+    let deleted = tree.delete(20);
+    assert_red_black(&deleted);
+    assert_eq!(deleted.get(&20), None);
+    assert_eq!(deleted.length(), 39);
+
+    let (greater, found, less) = tree.split(&10);
+    assert_eq!(found, Some(10));
+    assert_eq!(in_order(&greater), (11..40).rev().collect::<Vec<_>>());
+    assert_eq!(in_order(&less), (0..10).rev().collect::<Vec<_>>());
+
+    assert!(BinaryTree::<i32, (), ReverseOrder>::from_sorted_iter(0..3).is_err());
+    assert!(BinaryTree::<i32, (), ReverseOrder>::from_sorted_iter((0..3).rev()).is_ok());
+}
+
+#[test]
+fn tree_diff_skips_shared_subtrees() {
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+    static COMPARISONS: AtomicUsize = AtomicUsize::new(0);
+
+    #[derive(Default)]
+    struct Counting;
+    impl Comparator<i32> for Counting {
+        fn compare(&self, a: &i32, b: &i32) -> Ordering {
+            COMPARISONS.fetch_add(1, AtomicOrdering::Relaxed);
+            a.cmp(b)
+        }
+    }
+
+    let old: BinaryTree<i32, (), Counting> = BinaryTree::from_sorted_iter_unchecked(0..10000);
+    let new = old.delete(1234).insert(20000).insert(-5);
+
+    COMPARISONS.store(0, AtomicOrdering::Relaxed);
+    let changes: Vec<_> = old.diff(&new).collect();
+    assert!(COMPARISONS.load(AtomicOrdering::Relaxed) < 2000);
+    assert_eq!(changes, vec![Diff::Added(-5), Diff::Removed(1234), Diff::Added(20000)]);
+
+    //Nothing is shared with a tree built separately, but the first change still only costs a walk down
+    let rebuilt: BinaryTree<i32, (), Counting> = BinaryTree::from_sorted_iter_unchecked(1..10000);
+    COMPARISONS.store(0, AtomicOrdering::Relaxed);
+    assert_eq!(old.diff(&rebuilt).next(), Some(Diff::Removed(0)));
+    assert!(COMPARISONS.load(AtomicOrdering::Relaxed) < 100);
+}
+
+#[test]
+fn tree_insert_replaces() {
+    #[derive(Default)]
+    struct ByFirst;
+    impl Comparator<(i32, char)> for ByFirst {
+        fn compare(&self, a: &(i32, char), b: &(i32, char)) -> Ordering {
+            a.0.cmp(&b.0)
+        }
+    }
+
+    let tree: BinaryTree<(i32, char), (), ByFirst> = (0..20).map(|x| (x, 'a')).collect();
+    let (replaced, old) = tree.insert_returning_old((7, 'b'));
+    assert_eq!(old, Some((7, 'a')));
+    assert_eq!(replaced.get(&(7, ' ')), Some((7, 'b')));
+    assert_eq!(tree.get(&(7, ' ')), Some((7, 'a')));
+    assert_eq!(replaced.length(), 20);
+    assert_red_black(&replaced);
+
+    let (grown, old) = tree.insert_returning_old((20, 'b'));
+    assert_eq!(old, None);
+    assert_eq!(grown.length(), 21);
+}
+
+#[test]
+fn tree_update() {
+    let tree: BinaryTree<i32> = (0..100).collect();
+
+    let removed = tree.update(&40, |found| { assert_eq!(found, Some(&40)); None });
+    assert_red_black(&removed);
+    assert_eq!(in_order(&removed), (0..100).filter(|&x| x != 40).collect::<Vec<_>>());
+
+    let added = tree.update(&150, |found| { assert_eq!(found, None); Some(150) });
+    assert_red_black(&added);
+    assert_eq!(added.last(), Some(&150));
+
+    let untouched = tree.update(&150, |_| None);
+    assert_eq!(untouched, tree);
+    assert!(Rc::ptr_eq(&untouched.left(), &tree.left()));
+
+    //Removing every element one at a time keeps the tree balanced
+    let mut shrinking = tree.clone();
+    for x in (0..100).rev() {
+        shrinking = shrinking.update(&((x * 37) % 100), |_| None);
+        assert_red_black(&shrinking);
+        assert_eq!(shrinking.length(), x as usize);
+    }
+}
+
+#[test]
+fn tree_merge_with() {
+    let evens: BinaryTree<i32> = (0..50).map(|x| x * 2).collect();
+    let threes: BinaryTree<i32> = (0..34).map(|x| x * 3).collect();
+    let keep = &Only::Keep::<fn(&i32) -> Option<i32>>;
+    let drop = &Only::Drop::<fn(&i32) -> Option<i32>>;
+
+    let union = evens.merge_with(&threes, keep, keep, &|a, _| Some(*a));
+    assert_red_black(&union);
+    assert_eq!(in_order(&union), in_order(&evens.union(&threes)));
+
+    let sixes = evens.merge_with(&threes, drop, drop, &|a, _| Some(*a));
+    assert_red_black(&sixes);
+    assert_eq!(in_order(&sixes), (0..17).map(|x| x * 6).collect::<Vec<_>>());
+
+    //Odd multiples of three that are not multiples of nine
+    let odd_threes = evens.merge_with(&threes, drop, &Only::With(|x: &i32| if x % 9 == 0 { None } else { Some(*x) }), &|_, _| None);
+    assert_red_black(&odd_threes);
+    assert_eq!(in_order(&odd_threes), (0..34).map(|x| x * 3).filter(|x| x % 2 == 1 && x % 9 != 0).collect::<Vec<_>>());
+
+    let bigger: BinaryTree<i32> = (0..1000).collect();
+    let merged = bigger.merge_with(&binary_tree![2000], keep, keep, &|a, _| Some(*a));
+    //Everything left of the root is untouched and may only move down when the right side grows
+    fn shares(tree: &Rc<BinaryTree<i32>>, subtree: &Rc<BinaryTree<i32>>) -> bool {
+        Rc::ptr_eq(tree, subtree) || (!tree.is_empty() && (shares(&tree.left(), subtree) || shares(&tree.right(), subtree)))
+    }
+    assert!(shares(&Rc::new(merged), &bigger.left()));
+}
+
+#[test]
+fn tree_filter_partition_map() {
+    use tree::measure::Sum;
+
+    let tree: BinaryTree<i32> = (0..200).collect();
+
+    let evens = tree.filter(|x| x % 2 == 0);
+    assert_red_black(&evens);
+    assert_eq!(in_order(&evens), (0..100).map(|x| x * 2).collect::<Vec<_>>());
+    let all = tree.filter(|_| true);
+    assert!(Rc::ptr_eq(&all.left(), &tree.left()));
+    assert!(tree.filter(|_| false).is_empty());
+
+    let (small, large) = tree.partition(|&x| x < 37);
+    assert_red_black(&small);
+    assert_red_black(&large);
+    assert_eq!(in_order(&small), (0..37).collect::<Vec<_>>());
+    assert_eq!(in_order(&large), (37..200).collect::<Vec<_>>());
+
+    let doubled: BinaryTree<i64, Sum<i64>> = tree.map_monotone(&|x| *x as i64 * 2);
+    assert_red_black(&doubled);
+    assert_eq!(doubled.height(), tree.height());
+    assert_eq!(doubled.summary(), Sum((0..200).map(|x| x * 2).sum()));
+    assert_eq!(doubled.into_iter().collect::<Vec<_>>(), (0..200).map(|x| x * 2).collect::<Vec<_>>());
+}
+
+#[test]
+fn tree_relations() {
+    let tree: BinaryTree<i32> = (0..100).collect();
+    let evens = tree.filter(|x| x % 2 == 0);
+    let odds = tree.filter(|x| x % 2 == 1);
+
+    assert!(evens.is_subset(&tree));
+    assert!(tree.is_superset(&odds));
+    assert!(!tree.is_subset(&evens));
+    assert!(tree.is_subset(&tree.clone()));
+    assert!(evens.is_disjoint(&odds));
+    assert!(!evens.is_disjoint(&tree));
+    assert!(!tree.is_disjoint(&tree));
+    assert!(BinaryTree::<i32>::empty().is_subset(&evens));
+    assert!(BinaryTree::<i32>::empty().is_disjoint(&BinaryTree::empty()));
+    assert!(!evens.insert(1001).is_subset(&tree));
 }
@@ -0,0 +1,34 @@
+use std::cmp::{Ord, Ordering};
+
+/// A total order used by the trees in place of `Ord`, so the order of the elements can be chosen
+/// without wrapping them in a newtype.
+///
+/// Comparators are values, so an order can be configured at runtime, e.g. a collation for a locale
+/// picked by the user. Maps and sets keep the comparator they were built with and use it for every
+/// lookup and update; the plain `BinaryTree` methods use `C::default()` and the `*_by` methods take
+/// the order as a closure.
+pub trait Comparator<T: ?Sized> {
+    fn compare(&self, a: &T, b: &T) -> Ordering;
+}
+
+/// The order given by the element's `Ord` implementation.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct OrdComparator;
+
+impl<T: ?Sized + Ord> Comparator<T> for OrdComparator {
+    #[inline]
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// The opposite of the order given by `C`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct ReverseOrder<C = OrdComparator>(pub C);
+
+impl<T: ?Sized, C: Comparator<T>> Comparator<T> for ReverseOrder<C> {
+    #[inline]
+    fn compare(&self, a: &T, b: &T) -> Ordering {
+        self.0.compare(b, a)
+    }
+}
@@ -0,0 +1,49 @@
+use std::cmp::Ord;
+use std::ops::Add;
+
+/// A summary cached on every node of a `BinaryTree`.
+///
+/// `combine` must be associative and `identity` must be its neutral element, so the summary of a
+/// subtree can be rebuilt from the summaries of its children whenever the tree is rebalanced.
+pub trait Measure<E>: Clone {
+    fn identity() -> Self;
+    fn measure(elem: &E) -> Self;
+    fn combine(&self, other: &Self) -> Self;
+}
+
+impl<E> Measure<E> for () {
+    #[inline]
+    fn identity() -> Self {}
+    #[inline]
+    fn measure(_: &E) -> Self {}
+    #[inline]
+    fn combine(&self, _: &Self) -> Self {}
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct Count(pub usize);
+
+impl<E> Measure<E> for Count {
+    fn identity() -> Self { Count(0) }
+    fn measure(_: &E) -> Self { Count(1) }
+    fn combine(&self, other: &Self) -> Self { Count(self.0 + other.0) }
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug, Default)]
+pub struct Sum<T>(pub T);
+
+impl<T> Measure<T> for Sum<T> where T: Add<Output=T> + Default + Clone {
+    fn identity() -> Self { Sum(T::default()) }
+    fn measure(elem: &T) -> Self { Sum(elem.clone()) }
+    fn combine(&self, other: &Self) -> Self { Sum(self.0.clone() + other.0.clone()) }
+}
+
+//None is the identity so empty trees have no maximum
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct Max<T>(pub Option<T>);
+
+impl<T> Measure<T> for Max<T> where T: Ord + Clone {
+    fn identity() -> Self { Max(None) }
+    fn measure(elem: &T) -> Self { Max(Some(elem.clone())) }
+    fn combine(&self, other: &Self) -> Self { Max(self.0.clone().max(other.0.clone())) }
+}
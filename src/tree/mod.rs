@@ -1,5 +1,7 @@
 #[macro_use]
 pub mod binary_tree;
+pub mod measure;
+pub mod comparator;
 
 /*#[macro_use]
 pub mod tree;*/